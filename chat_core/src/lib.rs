@@ -32,6 +32,16 @@ pub struct Workspace {
     pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    Owner,
+    Admin,
+    Member,
+    Guest,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
 #[sqlx(type_name = "chat_type", rename_all = "snake_case")]
 #[serde(rename_all = "snake_case")]
@@ -49,7 +59,13 @@ pub struct Chat {
     pub name: Option<String>,
     pub r#type: ChatType,
     pub members: Vec<i64>,
+    // archived chats are read-only
+    pub archived_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
+    // messages from others not read by the user, only filled when listing chats
+    #[sqlx(default)]
+    #[serde(default)]
+    pub unread_count: i64,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
@@ -64,8 +80,40 @@ pub struct Message {
     pub id: i64,
     pub chat_id: i64,
     pub sender_id: i64,
+    // root message of the thread if this is a reply
+    pub thread_root_id: Option<i64>,
     pub content: String,
+    pub files: Vec<String>,
+    pub edited_at: Option<DateTime<Utc>>,
+    // deleted messages have empty content and files
+    pub deleted_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
+    // replies of a root message, only filled when listing messages
+    #[sqlx(default)]
+    #[serde(default)]
+    pub reply_count: i64,
+    #[sqlx(default)]
+    #[serde(default)]
+    pub last_reply_at: Option<DateTime<Utc>>,
+    // only filled when listing messages
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    // whether the user listing messages reacted with this emoji
+    pub reacted: bool,
+}
+
+impl WorkspaceRole {
+    /// Owners and admins could manage members and channels
+    pub fn is_admin(&self) -> bool {
+        matches!(self, WorkspaceRole::Owner | WorkspaceRole::Admin)
+    }
 }
 
 impl User {
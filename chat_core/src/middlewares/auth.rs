@@ -22,7 +22,7 @@ where
     let msg = match token {
         Ok(TypedHeader(Authorization(v))) => {
             let token = v.token();
-            match state.verify(token) {
+            match state.verify(token).await {
                 Ok(user) => {
                     let mut req = Request::from_parts(parts, body);
                     req.extensions_mut().insert(user);
@@ -72,7 +72,7 @@ mod tests {
 
     impl TokenVerify for AppState {
         type Error = ();
-        fn verify(&self, token: &str) -> Result<User, Self::Error> {
+        async fn verify(&self, token: &str) -> Result<User, Self::Error> {
             self.0.dk.verify(token).map_err(|_| ())
         }
     }
@@ -2,7 +2,7 @@ mod auth;
 mod request_id;
 mod server_time;
 
-use std::fmt;
+use std::{fmt, future::Future};
 
 pub use auth::verify_token;
 use axum::middleware::from_fn;
@@ -48,5 +48,6 @@ pub fn set_layer(router: Router) -> Router {
 
 pub trait TokenVerify {
     type Error: fmt::Debug;
-    fn verify(&self, token: &str) -> Result<User, Self::Error>;
+    // async so that implementations could check revoked tokens in db
+    fn verify(&self, token: &str) -> impl Future<Output = Result<User, Self::Error>> + Send;
 }
@@ -1,43 +1,211 @@
 use crate::User;
-use jwt_simple::prelude::*;
+use jwt_simple::{prelude::*, JWTError};
 
-const JWT_DURATION: u64 = 60 * 60 * 24 * 7; // 1 week
+const JWT_DURATION: u64 = 60 * 15; // 15 minutes, use refresh token to get a new one
 const JWT_ISSUER: &str = "chat_server";
 const JWT_AUDIENCE: &str = "chat_web";
+const JWT_CLOCK_SKEW: u64 = 60; // 1 minute
 
-pub struct EncodingKey(Ed25519KeyPair);
-pub struct DecodingKey(Ed25519PublicKey);
+/// Options to sign and verify tokens, usually loaded from `auth` of the app config
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JwtConfig {
+    #[serde(default = "default_issuer")]
+    pub issuer: String,
+    #[serde(default = "default_audience")]
+    pub audience: String,
+    // seconds an access token is valid for
+    #[serde(default = "default_duration")]
+    pub duration: u64,
+    // seconds of clock drift tolerated when checking timestamps
+    #[serde(default = "default_clock_skew")]
+    pub clock_skew: u64,
+    // reject tokens issued more than these seconds ago, regardless of expiration
+    #[serde(default)]
+    pub max_token_age: Option<u64>,
+}
+
+/// A public key in JWK format, see RFC 8037 for Ed25519 keys
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+}
+
+/// Served by chat_server at `/.well-known/jwks.json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+pub struct EncodingKey {
+    key: Ed25519KeyPair,
+    config: JwtConfig,
+}
+
+/// Keyset to verify tokens, the first key is the current one and the rest are
+/// previous keys kept around so that tokens signed before rotation stay valid.
+pub struct DecodingKey {
+    keys: Vec<Ed25519PublicKey>,
+    config: JwtConfig,
+}
 
 impl EncodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519KeyPair::from_pem(pem)?))
+        let key = Ed25519KeyPair::from_pem(pem)?;
+        let kid = key_id(&key.public_key());
+        Ok(Self {
+            key: key.with_key_id(&kid),
+            config: JwtConfig::default(),
+        })
+    }
+
+    pub fn with_config(self, config: JwtConfig) -> Self {
+        Self { config, ..self }
     }
 
     pub fn sign(&self, user: User) -> Result<String, jwt_simple::Error> {
-        let claims = Claims::with_custom_claims(user, Duration::from_secs(JWT_DURATION));
-        let claims = claims.with_issuer(JWT_ISSUER).with_audience(JWT_AUDIENCE);
-        self.0.sign(claims)
+        let claims = Claims::with_custom_claims(user, Duration::from_secs(self.config.duration));
+        let claims = claims
+            .with_issuer(&self.config.issuer)
+            .with_audience(&self.config.audience)
+            .with_jwt_id(uuid::Uuid::now_v7());
+        self.key.sign(claims)
     }
 }
 
 impl DecodingKey {
     pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
-        Ok(Self(Ed25519PublicKey::from_pem(pem)?))
+        Self::load_keyset([pem])
+    }
+
+    /// Load the current key followed by previous ones
+    pub fn load_keyset<'a>(
+        pems: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, jwt_simple::Error> {
+        let keys = pems
+            .into_iter()
+            .map(|pem| {
+                let key = Ed25519PublicKey::from_pem(pem)?;
+                let kid = key_id(&key);
+                Ok(key.with_key_id(&kid))
+            })
+            .collect::<Result<Vec<_>, jwt_simple::Error>>()?;
+        Self::try_new(keys)
+    }
+
+    /// Load keys published by chat_server, keys other than Ed25519 are ignored
+    pub fn from_jwks(jwks: &JwkSet) -> Result<Self, jwt_simple::Error> {
+        let keys = jwks
+            .keys
+            .iter()
+            .filter(|jwk| jwk.kty == "OKP" && jwk.crv == "Ed25519")
+            .map(|jwk| {
+                let raw = Base64UrlSafeNoPadding::decode_to_vec(&jwk.x, None)?;
+                Ok(Ed25519PublicKey::from_bytes(&raw)?.with_key_id(&jwk.kid))
+            })
+            .collect::<Result<Vec<_>, jwt_simple::Error>>()?;
+        Self::try_new(keys)
+    }
+
+    fn try_new(keys: Vec<Ed25519PublicKey>) -> Result<Self, jwt_simple::Error> {
+        if keys.is_empty() {
+            return Err(JWTError::InvalidPublicKey.into());
+        }
+        Ok(Self {
+            keys,
+            config: JwtConfig::default(),
+        })
+    }
+
+    pub fn with_config(self, config: JwtConfig) -> Self {
+        Self { config, ..self }
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        let keys = self
+            .keys
+            .iter()
+            .map(|key| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                x: Base64UrlSafeNoPadding::encode_to_string(key.to_bytes())
+                    .expect("encode public key failed"),
+                kid: key.key_id().clone().unwrap_or_default(),
+                alg: "EdDSA".to_string(),
+                key_use: "sig".to_string(),
+            })
+            .collect();
+        JwkSet { keys }
     }
 
-    #[allow(unused)]
     pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
-        let opst = VerificationOptions {
-            allowed_issuers: Some(HashSet::from_strings(&[JWT_ISSUER])),
-            allowed_audiences: Some(HashSet::from_strings(&[JWT_AUDIENCE])),
+        Ok(self.verify_claims(token)?.custom)
+    }
+
+    /// Verify the token and return all its claims, e.g. `jwt_id` for revocation check
+    pub fn verify_claims(&self, token: &str) -> Result<JWTClaims<User>, jwt_simple::Error> {
+        let opts = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_strings(&[&self.config.issuer])),
+            allowed_audiences: Some(HashSet::from_strings(&[&self.config.audience])),
+            time_tolerance: Some(Duration::from_secs(self.config.clock_skew)),
+            max_validity: self.config.max_token_age.map(Duration::from_secs),
             ..Default::default()
         };
 
-        let claims = self.0.verify_token::<User>(token, None)?;
-        Ok(claims.custom)
+        let metadata = Token::decode_metadata(token)?;
+        match metadata.key_id() {
+            Some(kid) => {
+                let key = self
+                    .keys
+                    .iter()
+                    .find(|key| key.key_id().as_deref() == Some(kid))
+                    .ok_or(JWTError::KeyIdentifierMismatch)?;
+                key.verify_token::<User>(token, Some(opts))
+            }
+            // tokens signed before key ids were introduced
+            None => self.keys[0].verify_token::<User>(token, Some(opts)),
+        }
     }
 }
 
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            issuer: default_issuer(),
+            audience: default_audience(),
+            duration: default_duration(),
+            clock_skew: default_clock_skew(),
+            max_token_age: None,
+        }
+    }
+}
+
+// derived from the key itself so that both servers agree without extra config
+fn key_id(key: &Ed25519PublicKey) -> String {
+    key.sha256_thumbprint()
+}
+
+fn default_issuer() -> String {
+    JWT_ISSUER.to_string()
+}
+
+fn default_audience() -> String {
+    JWT_AUDIENCE.to_string()
+}
+
+fn default_duration() -> u64 {
+    JWT_DURATION
+}
+
+fn default_clock_skew() -> u64 {
+    JWT_CLOCK_SKEW
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -58,4 +226,145 @@ mod tests {
 
         assert_eq!(user, claims);
     }
+
+    fn load_keys(config: JwtConfig) -> (EncodingKey, DecodingKey) {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+
+        let ek = EncodingKey::load(encoding_pem).unwrap();
+        let dk = DecodingKey::load(decoding_pem).unwrap().with_config(config);
+        (ek, dk)
+    }
+
+    // sign a token issued and expired at given seconds relative to now
+    fn sign_at(ek: &EncodingKey, issued: i64, expires: i64) -> String {
+        let now = Clock::now_since_epoch().as_secs() as i64;
+        let mut claims = Claims::with_custom_claims(User::new(1, "test", "test@qq.com"), 0.into())
+            .with_issuer(JWT_ISSUER)
+            .with_audience(JWT_AUDIENCE)
+            .with_jwt_id("jti");
+        claims.issued_at = Some(Duration::from_secs((now + issued) as u64));
+        claims.expires_at = Some(Duration::from_secs((now + expires) as u64));
+        ek.key.sign(claims).unwrap()
+    }
+
+    #[test]
+    fn jwt_should_reject_wrong_issuer_or_audience() {
+        let (ek, dk) = load_keys(JwtConfig::default());
+        let user = User::new(1, "test", "test@qq.com");
+
+        let other = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))
+            .unwrap()
+            .with_config(JwtConfig {
+                issuer: "other_server".to_string(),
+                ..Default::default()
+            });
+        let token = other.sign(user.clone()).unwrap();
+        assert!(dk.verify(&token).is_err());
+
+        let other = other.with_config(JwtConfig {
+            audience: "other_web".to_string(),
+            ..Default::default()
+        });
+        let token = other.sign(user.clone()).unwrap();
+        assert!(dk.verify(&token).is_err());
+
+        let token = ek.sign(user).unwrap();
+        assert!(dk.verify(&token).is_ok());
+    }
+
+    #[test]
+    fn jwt_should_reject_expired_token() {
+        let (ek, dk) = load_keys(JwtConfig {
+            clock_skew: 60,
+            ..Default::default()
+        });
+
+        // expired but within clock skew
+        let token = sign_at(&ek, -3600, -30);
+        assert!(dk.verify(&token).is_ok());
+
+        let token = sign_at(&ek, -3600, -120);
+        assert!(dk.verify(&token).is_err());
+    }
+
+    #[test]
+    fn jwt_should_reject_too_old_token() {
+        let (ek, dk) = load_keys(JwtConfig {
+            max_token_age: Some(600),
+            ..Default::default()
+        });
+
+        let token = sign_at(&ek, -300, 3600);
+        assert!(dk.verify(&token).is_ok());
+
+        let token = sign_at(&ek, -1200, 3600);
+        assert!(dk.verify(&token).is_err());
+    }
+
+    #[test]
+    fn jwt_should_verify_with_previous_keys() {
+        let old_ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem")).unwrap();
+        let new_pair = Ed25519KeyPair::generate();
+        let new_ek = EncodingKey::load(&new_pair.to_pem()).unwrap();
+        let user = User::new(1, "test", "test@qq.com");
+
+        let new_pk = new_pair.public_key().to_pem();
+        let dk = DecodingKey::load_keyset([
+            new_pk.as_str(),
+            include_str!("../../fixtures/decoding.pem"),
+        ])
+        .unwrap();
+        let token = old_ek.sign(user.clone()).unwrap();
+        assert_eq!(dk.verify(&token).unwrap(), user);
+        let token = new_ek.sign(user.clone()).unwrap();
+        assert_eq!(dk.verify(&token).unwrap(), user);
+
+        // old key retired
+        let dk = DecodingKey::load(&new_pk).unwrap();
+        let token = old_ek.sign(user).unwrap();
+        assert!(dk.verify(&token).is_err());
+    }
+
+    #[test]
+    fn jwks_should_round_trip() {
+        let (ek, dk) = load_keys(JwtConfig::default());
+        let jwks = dk.jwks();
+        assert_eq!(jwks.keys.len(), 1);
+        assert_eq!(jwks.keys[0].kid, key_id(&ek.key.public_key()));
+
+        let dk = DecodingKey::from_jwks(&jwks).unwrap();
+        let user = User::new(1, "test", "test@qq.com");
+        let token = ek.sign(user.clone()).unwrap();
+        assert_eq!(dk.verify(&token).unwrap(), user);
+
+        assert!(DecodingKey::from_jwks(&JwkSet::default()).is_err());
+    }
+
+    #[test]
+    fn jwt_config_should_have_defaults() {
+        let config: JwtConfig = serde_json::from_str(r#"{"issuer": "test"}"#).unwrap();
+        assert_eq!(config.issuer, "test");
+        assert_eq!(config.audience, JWT_AUDIENCE);
+        assert_eq!(config.duration, JWT_DURATION);
+        assert_eq!(config.max_token_age, None);
+    }
+
+    #[test]
+    fn jwt_should_have_unique_id() {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+
+        let ek = EncodingKey::load(encoding_pem).unwrap();
+        let dk = DecodingKey::load(decoding_pem).unwrap();
+
+        let user = User::new(1, "test", "test@qq.com");
+        let token1 = ek.sign(user.clone()).unwrap();
+        let token2 = ek.sign(user).unwrap();
+
+        let jti1 = dk.verify_claims(&token1).unwrap().jwt_id;
+        let jti2 = dk.verify_claims(&token2).unwrap().jwt_id;
+        assert!(jti1.is_some());
+        assert_ne!(jti1, jti2);
+    }
 }
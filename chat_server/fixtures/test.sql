@@ -34,3 +34,16 @@ INSERT INTO messages(chat_id, sender_id, content)
 (1, 3, 'How are you?'),
 (1, 1, 'Hello, world!'),
 (1, 1, 'Hello, world!');
+
+-- tchen owns acme, alice is an admin and daisy a guest
+UPDATE workspaces SET owner_id = 1 WHERE id = 1;
+INSERT INTO workspace_members(ws_id, user_id, role)
+  VALUES (1, 1, 'owner'),
+(1, 2, 'admin'),
+(1, 3, 'member'),
+(1, 4, 'member'),
+(1, 5, 'guest');
+
+-- tchen is also a member of foo
+INSERT INTO workspace_members(ws_id, user_id, role)
+  VALUES (2, 1, 'member');
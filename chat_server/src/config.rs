@@ -1,4 +1,5 @@
 use anyhow::Result;
+use chat_core::utils::JwtConfig;
 use serde::Deserialize;
 use serde_yaml::from_reader;
 use std::{fs, path::PathBuf};
@@ -7,12 +8,27 @@ use std::{fs, path::PathBuf};
 pub struct AppConfig {
     pub server: ServerConfig,
     pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub upload: UploadConfig,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AuthConfig {
     pub sk: String,
     pub pk: String,
+    // public keys of retired signing keys, tokens signed by them are still accepted
+    #[serde(default)]
+    pub previous_pks: Vec<String>,
+    // issuer, audience, clock skew etc. for access tokens
+    #[serde(flatten)]
+    pub jwt: JwtConfig,
+}
+
+impl AuthConfig {
+    /// Current public key followed by previous ones
+    pub fn verification_keys(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.pk.as_str()).chain(self.previous_pks.iter().map(|pk| pk.as_str()))
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -20,7 +36,63 @@ pub struct ServerConfig {
     pub host: String,
     pub port: u16,
     pub db_url: String,
-    pub base_dir: PathBuf,
+    // max characters of a message content
+    pub max_message_len: usize,
+    // days to keep deleted chats and their messages before purging them
+    #[serde(default = "default_chat_retention_days")]
+    pub chat_retention_days: u32,
+}
+
+fn default_chat_retention_days() -> u32 {
+    30
+}
+
+// where uploaded chat files are stored
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageConfig {
+    Local { base_dir: PathBuf },
+    S3(S3Config),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UploadConfig {
+    // max bytes of a single file
+    pub max_file_size: usize,
+    // max bytes of the whole upload request
+    pub max_request_size: usize,
+    // mime types could be uploaded, e.g. `image/*` or `application/pdf`, empty to allow all
+    #[serde(default)]
+    pub allowed_types: Vec<String>,
+    // mime types rejected even if allowed above
+    #[serde(default)]
+    pub denied_types: Vec<String>,
+    // max width/height of thumbnails generated for uploaded images
+    #[serde(default)]
+    pub thumbnail_sizes: Vec<u32>,
+}
+
+impl UploadConfig {
+    pub fn is_type_allowed(&self, mime: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix("/*") {
+            Some(prefix) => mime.split('/').next() == Some(prefix),
+            None => pattern == mime,
+        };
+
+        if self.denied_types.iter().any(matches) {
+            return false;
+        }
+        self.allowed_types.is_empty() || self.allowed_types.iter().any(matches)
+    }
 }
 
 impl AppConfig {
@@ -43,3 +115,32 @@ impl AppConfig {
         Ok(config?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upload_type_should_be_checked() {
+        let config = UploadConfig {
+            max_file_size: 1024,
+            max_request_size: 4096,
+            allowed_types: vec!["image/*".to_string(), "application/pdf".to_string()],
+            denied_types: vec!["image/svg+xml".to_string()],
+            thumbnail_sizes: vec![],
+        };
+
+        assert!(config.is_type_allowed("image/png"));
+        assert!(config.is_type_allowed("application/pdf"));
+        assert!(!config.is_type_allowed("image/svg+xml"));
+        assert!(!config.is_type_allowed("text/html"));
+        assert!(!config.is_type_allowed("imagex/png"));
+
+        let config = UploadConfig {
+            allowed_types: vec![],
+            ..config
+        };
+        assert!(config.is_type_allowed("text/html"));
+        assert!(!config.is_type_allowed("image/svg+xml"));
+    }
+}
@@ -15,23 +15,82 @@ pub enum AppError {
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
 
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
     #[error("email already exists: {0}")]
     EmailAlreadyExists(String),
 
+    #[error("workspace already exists: {0}")]
+    WorkspaceAlreadyExists(String),
+
+    #[error("invite error: {0}")]
+    InviteError(String),
+
     #[error("create chat error: {0}")]
     CreateChatError(String),
 
     #[error("update chat error: {0}")]
     UpdateChatError(String),
 
+    #[error("create message error: {0}")]
+    CreateMessageError(String),
+
+    #[error("update message error: {0}")]
+    UpdateMessageError(String),
+
+    #[error("invalid reaction: {0}")]
+    InvalidReaction(String),
+
+    #[error("chat file error: {0}")]
+    ChatFileError(String),
+
     #[error("upload error: {0}")]
     UploadError(#[from] MultipartError),
 
+    #[error("file too large: {0}")]
+    FileTooLarge(String),
+
+    #[error("unsupported file type: {0}")]
+    UnsupportedFileType(String),
+
     #[error("std error: {0}")]
     StdError(#[from] std::io::Error),
 
+    #[error("thumbnail error: {0}")]
+    ThumbnailError(String),
+
+    #[error("storage error: {0}")]
+    StorageError(object_store::Error),
+
     #[error("not found: {0}")]
     NotFound(String),
+
+    #[error("not chat member: {0}")]
+    NotChatMember(String),
+
+    #[error("not workspace member: {0}")]
+    NotWorkspaceMember(String),
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+}
+
+impl From<object_store::Error> for AppError {
+    fn from(e: object_store::Error) -> Self {
+        match e {
+            object_store::Error::NotFound { path, .. } => {
+                AppError::NotFound(format!("file {} not found", path))
+            }
+            e => AppError::StorageError(e),
+        }
+    }
+}
+
+impl From<image::ImageError> for AppError {
+    fn from(e: image::ImageError) -> Self {
+        AppError::ThumbnailError(e.to_string())
+    }
 }
 
 impl IntoResponse for AppError {
@@ -40,12 +99,26 @@ impl IntoResponse for AppError {
             AppError::SqlxError(_) => status::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Argon2Error(_) => status::StatusCode::UNPROCESSABLE_ENTITY,
             AppError::JwtError(_) => status::StatusCode::FORBIDDEN,
+            AppError::InvalidToken(_) => status::StatusCode::UNAUTHORIZED,
             AppError::EmailAlreadyExists(_) => status::StatusCode::CONFLICT,
+            AppError::WorkspaceAlreadyExists(_) => status::StatusCode::CONFLICT,
+            AppError::InviteError(_) => status::StatusCode::BAD_REQUEST,
             AppError::CreateChatError(_) => status::StatusCode::BAD_REQUEST,
             AppError::UpdateChatError(_) => status::StatusCode::BAD_REQUEST,
-            AppError::UploadError(_) => status::StatusCode::BAD_REQUEST,
+            AppError::CreateMessageError(_) => status::StatusCode::BAD_REQUEST,
+            AppError::UpdateMessageError(_) => status::StatusCode::BAD_REQUEST,
+            AppError::InvalidReaction(_) => status::StatusCode::BAD_REQUEST,
+            AppError::ChatFileError(_) => status::StatusCode::BAD_REQUEST,
+            AppError::UploadError(ref e) => e.status(),
+            AppError::FileTooLarge(_) => status::StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::UnsupportedFileType(_) => status::StatusCode::UNSUPPORTED_MEDIA_TYPE,
             AppError::StdError(_) => status::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::ThumbnailError(_) => status::StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::StorageError(_) => status::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::NotFound(_) => status::StatusCode::NOT_FOUND,
+            AppError::NotChatMember(_) => status::StatusCode::FORBIDDEN,
+            AppError::NotWorkspaceMember(_) => status::StatusCode::FORBIDDEN,
+            AppError::PermissionDenied(_) => status::StatusCode::FORBIDDEN,
         };
 
         (
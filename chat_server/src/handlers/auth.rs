@@ -1,14 +1,38 @@
-use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Extension, Json};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use chat_core::User;
+use chrono::DateTime;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    models::{CreateUser, SignInUser},
+    models::{CreateUser, RefreshToken, SignInUser},
     AppError, AppState,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
-struct AuthOutput {
-    token: String,
+pub(crate) struct AuthOutput {
+    pub(crate) token: String,
+    pub(crate) refresh_token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SignOut {
+    refresh_token: Option<String>,
+}
+
+impl AppState {
+    // short-lived access token along with a refresh token to renew it
+    pub(crate) async fn issue_tokens(&self, user: User) -> Result<AuthOutput, AppError> {
+        let refresh_token = self.create_refresh_token(user.id).await?;
+        let token = self.ek.sign(user)?;
+        Ok(AuthOutput {
+            token,
+            refresh_token,
+        })
+    }
 }
 
 pub(crate) async fn signup_handler(
@@ -16,8 +40,7 @@ pub(crate) async fn signup_handler(
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
-    let body = Json(AuthOutput { token });
+    let body = Json(state.issue_tokens(user).await?);
     Ok((StatusCode::CREATED, body))
 }
 
@@ -29,24 +52,69 @@ pub(crate) async fn signin_handler(
 
     match user {
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            Ok((StatusCode::OK, Json(AuthOutput { token })).into_response())
+            let body = Json(state.issue_tokens(user).await?);
+            Ok((StatusCode::OK, body).into_response())
         }
         None => Err(AppError::NotFound("user not found".to_string())),
     }
 }
 
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshToken>,
+) -> Result<impl IntoResponse, AppError> {
+    let user_id = state.rotate_refresh_token(&input.refresh_token).await?;
+    let user = state
+        .find_user_by_id(user_id)
+        .await?
+        .ok_or_else(|| AppError::InvalidToken("user not found".to_string()))?;
+
+    Ok((StatusCode::OK, Json(state.issue_tokens(user).await?)))
+}
+
+/// Revoke the current access token, and the refresh token if provided.
+/// The body is optional.
+pub(crate) async fn signout_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    input: Option<Json<SignOut>>,
+) -> Result<impl IntoResponse, AppError> {
+    let input = input.map(|Json(input)| input).unwrap_or_default();
+    let claims = state.dk.verify_claims(bearer.token())?;
+    if let (Some(jti), Some(expires_at)) = (claims.jwt_id, claims.expires_at) {
+        let expires_at = DateTime::from_timestamp(expires_at.as_secs() as i64, 0)
+            .ok_or_else(|| AppError::InvalidToken("invalid expiration".to_string()))?;
+        state.revoke_token(&jti, expires_at).await?;
+    }
+
+    if let Some(refresh_token) = input.refresh_token {
+        state.revoke_refresh_token(&refresh_token, user.id).await?;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Public keys to verify access tokens, for other services like notify_server
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.dk.jwks())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
     use axum::http::StatusCode;
+    use chat_core::{
+        middlewares::TokenVerify,
+        utils::{DecodingKey, JwkSet},
+    };
     use http_body_util::BodyExt;
 
     #[tokio::test]
     async fn signup_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("acme", "Tian Chen", "tyr@acme.org", "123456");
+        let input = CreateUser::new("newco", "Tian Chen", "tyr@newco.org", "123456");
         let ret = signup_handler(State(state), Json(input))
             .await?
             .into_response();
@@ -84,4 +152,122 @@ mod tests {
 
         Ok(())
     }
+
+    async fn signin(state: &AppState) -> Result<AuthOutput> {
+        let input = SignInUser {
+            email: "tchen@acme.org".to_string(),
+            password: "123456".to_string(),
+        };
+        let ret = signin_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    #[tokio::test]
+    async fn refresh_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = signin(&state).await?;
+
+        let input = RefreshToken {
+            refresh_token: output.refresh_token.clone(),
+        };
+        let ret = refresh_handler(State(state.clone()), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let refreshed: AuthOutput = serde_json::from_slice(&body)?;
+        assert_ne!(refreshed.refresh_token, output.refresh_token);
+        let user = state.dk.verify(&refreshed.token)?;
+        assert_eq!(user.email, "tchen@acme.org");
+
+        // refresh token is rotated
+        let input = RefreshToken {
+            refresh_token: output.refresh_token,
+        };
+        let ret = refresh_handler(State(state.clone()), Json(input)).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_should_revoke_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = signin(&state).await?;
+        // session on another device
+        let other = signin(&state).await?;
+        let user = TokenVerify::verify(&state, &output.token).await?;
+
+        let bearer = Authorization::bearer(&output.token)?;
+        let input = SignOut {
+            refresh_token: Some(output.refresh_token.clone()),
+        };
+        let ret = signout_handler(
+            Extension(user),
+            State(state.clone()),
+            TypedHeader(bearer),
+            Some(Json(input)),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = TokenVerify::verify(&state, &output.token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        let input = RefreshToken {
+            refresh_token: output.refresh_token,
+        };
+        let ret = refresh_handler(State(state.clone()), Json(input)).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        // retrying a signed out token is not a leak, other sessions are kept
+        let input = RefreshToken {
+            refresh_token: other.refresh_token,
+        };
+        refresh_handler(State(state), Json(input)).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_should_work_without_body() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = signin(&state).await?;
+        let user = TokenVerify::verify(&state, &output.token).await?;
+
+        let bearer = Authorization::bearer(&output.token)?;
+        let ret = signout_handler(
+            Extension(user),
+            State(state.clone()),
+            TypedHeader(bearer),
+            None,
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = TokenVerify::verify(&state, &output.token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwks_should_verify_issued_tokens() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let output = signin(&state).await?;
+
+        let ret = jwks_handler(State(state)).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let jwks: JwkSet = serde_json::from_slice(&body)?;
+
+        let dk = DecodingKey::from_jwks(&jwks)?;
+        let user = dk.verify(&output.token)?;
+        assert_eq!(user.email, "tchen@acme.org");
+        Ok(())
+    }
 }
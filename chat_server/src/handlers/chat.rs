@@ -1,54 +1,390 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
     response::IntoResponse,
     Extension, Json,
 };
-use chat_core::User;
+use chat_core::{Chat, ChatType, User, WorkspaceRole};
 
 use crate::{
-    models::{CreateChat, UpdateChat},
+    models::{CreateChat, ListChats, MarkRead, UpdateChat},
     AppError, AppState,
 };
 
 pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<ListChats>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chats = state.fetch_chats(user.ws_id).await?;
+    let chats = state.fetch_chats(user.ws_id, user.id, &input).await?;
     Ok((StatusCode::OK, Json(chats)))
 }
 
+/// Public channels of the workspace for members to discover and join
+pub(crate) async fn list_channels_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let channels = state.fetch_public_channels(user.ws_id).await?;
+    Ok((StatusCode::OK, Json(channels)))
+}
+
+/// Open the direct chat with another member, created on first use
+pub(crate) async fn open_dm_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(user_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = match state.find_direct_chat(user.ws_id, user.id, user_id).await? {
+        Some(chat) => chat,
+        None => {
+            verify_can_create_chat(&state, &user).await?;
+            state.open_direct_chat(user.ws_id, user.id, user_id).await?
+        }
+    };
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn join_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state.join_chat(id, &user).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.leave_chat(&chat, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Mark chat as read up to a message, or the latest one if not given.
+/// Responds with no content for an empty chat.
+pub(crate) async fn mark_read_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    input: Option<Json<MarkRead>>,
+) -> Result<impl IntoResponse, AppError> {
+    let input = input.map(|Json(input)| input).unwrap_or_default();
+    match state.mark_chat_read(chat.id, user.id, &input).await? {
+        Some(read) => Ok((StatusCode::OK, Json(read)).into_response()),
+        None => Ok(StatusCode::NO_CONTENT.into_response()),
+    }
+}
+
 pub(crate) async fn create_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.create_chat(input, user.ws_id).await?;
+    verify_can_create_chat(&state, &user).await?;
+    let chat = state.create_chat(input, user.ws_id, user.id).await?;
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
+/// Adding members follows the create rule, other changes the manage rule
 pub(crate) async fn update_chat_handler(
-    Path(id): Path<String>,
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
     State(state): State<AppState>,
     Json(input): Json<UpdateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = match id.parse::<i64>() {
-        Ok(id) => state.update_chat_by_id(id, input).await?,
-        Err(_) => return Err(AppError::UpdateChatError("Invalid chat id".to_string())),
-    };
-
+    if !input.add_members.is_empty() {
+        verify_can_create_chat(&state, &user).await?;
+    }
+    if input.name.is_some() || !input.remove_members.is_empty() || input.public.is_some() {
+        verify_can_manage_chat(&state, &user, &chat).await?;
+    }
+    let chat = state.update_chat_by_id(chat.id, input).await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
 pub(crate) async fn delete_chat_handler(
-    Path(id): Path<String>,
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_can_manage_chat(&state, &user, &chat).await?;
+    state.delete_chat_by_id(chat.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn archive_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_can_manage_chat(&state, &user, &chat).await?;
+    let chat = state.archive_chat(chat.id, true).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+pub(crate) async fn unarchive_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    match id.parse::<i64>() {
-        Ok(id) => state.delete_chat_by_id(id).await?,
-        Err(_) => return Err(AppError::UpdateChatError("Invalid chat id".to_string())),
+    verify_can_manage_chat(&state, &user, &chat).await?;
+    let chat = state.archive_chat(chat.id, false).await?;
+    Ok((StatusCode::OK, Json(chat)))
+}
+
+/// Channels could only be archived or deleted by workspace admins, other
+/// chats by any member except guests.
+async fn verify_can_manage_chat(
+    state: &AppState,
+    user: &User,
+    chat: &Chat,
+) -> Result<(), AppError> {
+    let role = state.get_member_role(user.ws_id, user.id).await?;
+    let allowed = match chat.r#type {
+        ChatType::PublicChannel | ChatType::PrivateChannel => role.is_admin(),
+        ChatType::Single | ChatType::Group => role != WorkspaceRole::Guest,
     };
+    if !allowed {
+        return Err(AppError::PermissionDenied(format!(
+            "{:?} could not manage chat {}",
+            role, chat.id
+        )));
+    }
+    Ok(())
+}
 
-    Ok(StatusCode::NO_CONTENT)
+async fn verify_can_create_chat(state: &AppState, user: &User) -> Result<(), AppError> {
+    let role = state.get_member_role(user.ws_id, user.id).await?;
+    if role == WorkspaceRole::Guest {
+        return Err(AppError::PermissionDenied(
+            "guests could not create chats".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    async fn get_user(state: &AppState, email: &str) -> Result<User> {
+        Ok(state
+            .find_user_by_email(email)
+            .await?
+            .expect("user should exist"))
+    }
+
+    #[tokio::test]
+    async fn guest_should_not_create_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let guest = get_user(&state, "daisy@acme.org").await?;
+        let input = CreateChat::new("", &[1, 5], false);
+        let ret = create_chat_handler(Extension(guest), State(state.clone()), Json(input)).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let member = get_user(&state, "bob@acme.org").await?;
+        let input = CreateChat::new("", &[1, 3], false);
+        let ret = create_chat_handler(Extension(member), State(state), Json(input))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn channel_should_be_deleted_by_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let channel = state.find_chat_by_id(2).await?.unwrap();
+
+        let member = get_user(&state, "bob@acme.org").await?;
+        let ret = delete_chat_handler(
+            Extension(member.clone()),
+            Extension(channel.clone()),
+            State(state.clone()),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let admin = get_user(&state, "alice@acme.org").await?;
+        let ret = delete_chat_handler(Extension(admin), Extension(channel), State(state.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(state.find_chat_by_id(2).await?.is_none());
+
+        // group chat could be deleted by a member
+        let group = state.find_chat_by_id(4).await?.unwrap();
+        let ret = delete_chat_handler(Extension(member), Extension(group), State(state))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn channel_should_be_updated_by_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let channel = state.find_chat_by_id(2).await?.unwrap();
+        let member = get_user(&state, "bob@acme.org").await?;
+
+        for input in [
+            UpdateChat {
+                name: Some("renamed".to_string()),
+                ..Default::default()
+            },
+            UpdateChat {
+                remove_members: vec![2],
+                ..Default::default()
+            },
+            UpdateChat {
+                public: Some(true),
+                ..Default::default()
+            },
+        ] {
+            let ret = update_chat_handler(
+                Extension(member.clone()),
+                Extension(channel.clone()),
+                State(state.clone()),
+                Json(input),
+            )
+            .await;
+            assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        }
+
+        // members could still invite others
+        let input = UpdateChat {
+            add_members: vec![4],
+            ..Default::default()
+        };
+        update_chat_handler(
+            Extension(member),
+            Extension(channel.clone()),
+            State(state.clone()),
+            Json(input),
+        )
+        .await?;
+
+        let admin = get_user(&state, "alice@acme.org").await?;
+        let input = UpdateChat {
+            name: Some("renamed".to_string()),
+            ..Default::default()
+        };
+        update_chat_handler(
+            Extension(admin),
+            Extension(channel),
+            State(state.clone()),
+            Json(input),
+        )
+        .await?;
+        let chat = state.find_chat_by_id(2).await?.unwrap();
+        assert_eq!(chat.name.as_deref(), Some("renamed"));
+        assert!(chat.members.contains(&4));
+
+        // guests could not add members, just like they could not create chats
+        let guest = get_user(&state, "daisy@acme.org").await?;
+        let general = state.find_chat_by_id(1).await?.unwrap();
+        let input = UpdateChat {
+            add_members: vec![6],
+            ..Default::default()
+        };
+        let ret = update_chat_handler(
+            Extension(guest),
+            Extension(general),
+            State(state),
+            Json(input),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn mark_read_handler_should_work_without_body() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = get_user(&state, "bob@acme.org").await?;
+        let chat = state.find_chat_by_id(1).await?.unwrap();
+        let ret = mark_read_handler(
+            Extension(user.clone()),
+            Extension(chat),
+            State(state.clone()),
+            None,
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        // private channel 2 has no messages yet
+        let chat = state.find_chat_by_id(2).await?.unwrap();
+        let ret = mark_read_handler(Extension(user), Extension(chat), State(state), None)
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_dm_handler_should_reuse_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = get_user(&state, "tchen@acme.org").await?;
+        let ret = open_dm_handler(Extension(user.clone()), State(state.clone()), Path(2))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        // guest could open existing direct chats only
+        let guest = get_user(&state, "daisy@acme.org").await?;
+        let ret = open_dm_handler(Extension(guest), State(state), Path(2)).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn channel_should_be_archived_by_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let channel = state.find_chat_by_id(1).await?.unwrap();
+
+        let member = get_user(&state, "bob@acme.org").await?;
+        let ret = archive_chat_handler(
+            Extension(member),
+            Extension(channel.clone()),
+            State(state.clone()),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let admin = get_user(&state, "alice@acme.org").await?;
+        archive_chat_handler(
+            Extension(admin.clone()),
+            Extension(channel.clone()),
+            State(state.clone()),
+        )
+        .await?;
+        let ret = list_chat_handler(
+            Extension(admin.clone()),
+            State(state.clone()),
+            Query(ListChats::default()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        assert!(state
+            .find_chat_by_id(1)
+            .await?
+            .unwrap()
+            .archived_at
+            .is_some());
+
+        unarchive_chat_handler(Extension(admin), Extension(channel), State(state.clone())).await?;
+        assert!(state
+            .find_chat_by_id(1)
+            .await?
+            .unwrap()
+            .archived_at
+            .is_none());
+        Ok(())
+    }
 }
@@ -2,6 +2,7 @@ mod auth;
 mod chat;
 mod msgs;
 mod user;
+mod workspace;
 
 use axum::{http::StatusCode, response::IntoResponse};
 
@@ -9,6 +10,7 @@ pub(crate) use auth::*;
 pub(crate) use chat::*;
 pub(crate) use msgs::*;
 pub(crate) use user::*;
+pub(crate) use workspace::*;
 
 pub(crate) async fn index_handler() -> impl IntoResponse {
     (StatusCode::OK, "Hello, World!")
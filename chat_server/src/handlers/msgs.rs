@@ -1,58 +1,205 @@
+use std::ops::Bound;
+
 use axum::{
-    extract::{Multipart, Path, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    body::{Body, Bytes},
+    extract::{Multipart, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Extension, Json,
 };
-use chat_core::User;
-use tokio::fs;
+use axum_extra::{
+    headers::{
+        AcceptRanges, ContentLength, ContentRange, ContentType, ETag, HeaderMapExt, IfNoneMatch,
+        Range,
+    },
+    TypedHeader,
+};
+use chat_core::{Chat, User};
+use serde::Deserialize;
 use tracing::warn;
 
 use crate::{
-    models::{ChatFile, CreateMessage},
+    models::{ChatFile, CreateMessage, ListMessages, UpdateMessage},
     AppError, AppState,
 };
 
+// chat files are content addressed, so they never change
+const FILE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DownloadFile {
+    // max width/height of the image thumbnail
+    size: Option<u32>,
+}
+
 pub(crate) async fn send_msg_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Path(id): Path<i64>,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msg = state.create_message(&input).await?;
+    let msg = state.create_message(&input, id, &user).await?;
     Ok((StatusCode::OK, Json(msg)))
 }
 
 pub(crate) async fn list_msg_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<i64>,
+    Query(input): Query<ListMessages>,
 ) -> Result<impl IntoResponse, AppError> {
-    let msgs = state.fetch_messages(id).await?;
+    let msgs = state.list_messages(&input, id, user.id).await?;
     Ok((StatusCode::OK, Json(msgs)))
 }
 
+/// Replies in the thread of a message, paged as messages of the chat
+pub(crate) async fn list_replies_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, msg_id)): Path<(i64, i64)>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    let msgs = state.list_replies(&input, chat.id, msg_id, user.id).await?;
+    Ok((StatusCode::OK, Json(msgs)))
+}
+
+pub(crate) async fn update_msg_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, msg_id)): Path<(i64, i64)>,
+    Json(input): Json<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_can_modify_message(&state, &user, chat.id, msg_id).await?;
+    let msg = state.update_message(chat.id, msg_id, &input).await?;
+    Ok((StatusCode::OK, Json(msg)))
+}
+
+pub(crate) async fn delete_msg_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, msg_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_can_modify_message(&state, &user, chat.id, msg_id).await?;
+    state.delete_message(chat.id, msg_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_msg_edits_handler(
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, msg_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let edits = state.fetch_message_edits(chat.id, msg_id).await?;
+    Ok((StatusCode::OK, Json(edits)))
+}
+
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, msg_id, emoji)): Path<(i64, i64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.add_reaction(chat.id, msg_id, user.id, &emoji).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, msg_id, emoji)): Path<(i64, i64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .remove_reaction(chat.id, msg_id, user.id, &emoji)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// messages could be changed by their sender or workspace admins
+async fn verify_can_modify_message(
+    state: &AppState,
+    user: &User,
+    chat_id: i64,
+    msg_id: i64,
+) -> Result<(), AppError> {
+    let msg = state
+        .find_message(chat_id, msg_id)
+        .await?
+        .filter(|msg| msg.deleted_at.is_none())
+        .ok_or_else(|| AppError::NotFound(format!("message {}", msg_id)))?;
+    if msg.sender_id == user.id {
+        return Ok(());
+    }
+
+    let role = state.get_member_role(user.ws_id, user.id).await?;
+    if !role.is_admin() {
+        return Err(AppError::PermissionDenied(format!(
+            "user {} could not modify message {}",
+            user.id, msg_id
+        )));
+    }
+    Ok(())
+}
+
+/// Upload files with multipart, returns urls of the uploaded files. The whole
+/// request is capped by `DefaultBodyLimit`, each file is checked against the
+/// upload config by its size and sniffed mime type.
 pub(crate) async fn upload_file_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let ws_id = user.ws_id;
-    let base_dir = &state.config.server.base_dir;
+    let config = &state.config.upload;
+    let mut total_size = 0;
     let mut files = Vec::new();
 
-    while let Some(field) = multipart.next_field().await? {
-        let filename = field.file_name().map(|s| s.to_string());
-        let (Some(filename), Ok(data)) = (filename, field.bytes().await) else {
+    while let Some(mut field) = multipart.next_field().await? {
+        let Some(filename) = field.file_name().map(|s| s.to_string()) else {
             warn!("Failed to read multipart field");
             continue;
         };
 
+        let mut data = Vec::new();
+        while let Some(chunk) = field.chunk().await? {
+            total_size += chunk.len();
+            data.extend_from_slice(&chunk);
+            if data.len() > config.max_file_size {
+                return Err(AppError::FileTooLarge(format!(
+                    "{} exceeds {} bytes",
+                    filename, config.max_file_size
+                )));
+            }
+            if total_size > config.max_request_size {
+                return Err(AppError::FileTooLarge(format!(
+                    "upload exceeds {} bytes",
+                    config.max_request_size
+                )));
+            }
+        }
+
         let file = ChatFile::new(ws_id, &filename, &data);
-        let path = file.path(base_dir);
+        let mime = file.mime();
+        if !config.is_type_allowed(mime.essence_str()) {
+            return Err(AppError::UnsupportedFileType(format!(
+                "{} of type {}",
+                filename, mime
+            )));
+        }
 
-        if path.exists() {
-            warn!("File {} already exists: {:?}", filename, path);
+        let key = file.key();
+        if state.store.exists(&key).await? {
+            warn!("File {} already exists: {}", filename, key);
         } else {
-            fs::create_dir_all(path.parent().expect("file path parent should exists")).await?;
-            fs::write(path, data).await?;
+            let data = Bytes::from(data);
+            state.store.put(&key, data.clone()).await?;
+            // thumbnails are nice to have, the original is still served without them
+            if let Err(e) = state.generate_thumbnails(&file, data).await {
+                warn!("Failed to generate thumbnails for {}: {}", key, e);
+            }
         }
         files.push(file.url());
     }
@@ -60,21 +207,418 @@ pub(crate) async fn upload_file_handler(
     Ok((StatusCode::OK, Json(files)))
 }
 
+/// Stream a chat file from the file store. Range requests are supported for
+/// seeking in audio/video, and since files are content addressed the hash is
+/// used as an ETag so clients can cache them forever. For images `?size=256`
+/// serves the smallest thumbnail fitting the size, or the original if none.
 pub(crate) async fn download_file_handler(
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(i64, String)>,
-) -> Result<impl IntoResponse, AppError> {
-    let base_dir = &state.config.server.base_dir.join(ws_id.to_string());
-    let path = base_dir.join(path);
+    Query(input): Query<DownloadFile>,
+    if_none_match: Option<TypedHeader<IfNoneMatch>>,
+    range: Option<TypedHeader<Range>>,
+) -> Result<Response, AppError> {
+    let file: ChatFile = format!("/files/{}/{}", ws_id, path)
+        .parse()
+        .map_err(|_| AppError::NotFound("file not found".to_string()))?;
+
+    let thumbnail = match input.size {
+        Some(size) => state.find_thumbnail(&file, size).await?,
+        None => None,
+    };
+    let (key, etag, mime) = match thumbnail {
+        Some((size, key)) => (
+            key,
+            format!("\"{}-{}\"", file.hash, size),
+            file.thumbnail_mime(),
+        ),
+        None => (file.key(), format!("\"{}\"", file.hash), file.mime()),
+    };
 
-    if !path.exists() {
+    let Some(size) = state.store.size(&key).await? else {
         return Err(AppError::NotFound("file not found".to_string()));
-    }
+    };
 
-    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-    // TODO: streaming
-    let body = fs::read(path).await?;
+    let etag: ETag = etag.parse().expect("file hash should be a valid etag");
     let mut headers = HeaderMap::new();
-    headers.insert("content-type", mime.to_string().parse().unwrap());
-    Ok((headers, body))
+    headers.typed_insert(etag.clone());
+    headers.typed_insert(AcceptRanges::bytes());
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(FILE_CACHE_CONTROL),
+    );
+    // browsers must not guess another type, e.g. html from a text file
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+
+    if let Some(TypedHeader(if_none_match)) = if_none_match {
+        if !if_none_match.precondition_passes(&etag) {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+    }
+
+    headers.typed_insert(ContentType::from(mime));
+
+    let (status, range) = match range {
+        Some(TypedHeader(range)) => match byte_range(&range, size) {
+            Some(range) => (StatusCode::PARTIAL_CONTENT, Some(range)),
+            None => {
+                headers.typed_insert(ContentRange::unsatisfied_bytes(size));
+                return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+            }
+        },
+        None => (StatusCode::OK, None),
+    };
+
+    match &range {
+        Some(range) => {
+            let content_range = ContentRange::bytes(range.clone(), size)
+                .expect("satisfiable range should be valid");
+            headers.typed_insert(content_range);
+            headers.typed_insert(ContentLength(range.end - range.start));
+        }
+        None => headers.typed_insert(ContentLength(size)),
+    }
+
+    let stream = state.store.stream(&key, range).await?;
+    Ok((status, headers, Body::from_stream(stream)).into_response())
+}
+
+// only the first range is served, multipart/byteranges is not supported
+fn byte_range(range: &Range, size: u64) -> Option<std::ops::Range<u64>> {
+    let (start, end) = range.satisfiable_ranges(size).next()?;
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => (end + 1).min(size),
+        Bound::Excluded(end) => end.min(size),
+        Bound::Unbounded => size,
+    };
+
+    (start < end).then_some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use axum::{
+        extract::{DefaultBodyLimit, Request},
+        routing::post,
+        Router,
+    };
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    const BOUNDARY: &str = "chat-upload-boundary";
+
+    async fn upload(state: &AppState, files: &[(&str, &[u8])]) -> Result<Response> {
+        let app = Router::new()
+            .route(
+                "/upload",
+                post(upload_file_handler)
+                    .layer(DefaultBodyLimit::max(state.config.upload.max_request_size)),
+            )
+            .with_state(state.clone());
+
+        let mut body = Vec::new();
+        for (filename, data) in files {
+            body.extend_from_slice(
+                format!(
+                    "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\n",
+                    BOUNDARY, filename
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+
+        let user = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+        let mut req = Request::builder()
+            .method("POST")
+            .uri("/upload")
+            .header(
+                "content-type",
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from(body))?;
+        req.extensions_mut().insert(user);
+
+        Ok(app.oneshot(req).await?)
+    }
+
+    #[tokio::test]
+    async fn upload_file_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let png: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+        let res = upload(
+            &state,
+            &[("hello.txt", b"hello upload"), ("photo.txt", png)],
+        )
+        .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await?.to_bytes();
+        let urls: Vec<String> = serde_json::from_slice(&body)?;
+        assert_eq!(urls.len(), 2);
+        assert!(urls[0].ends_with(".txt"));
+        // extension comes from content rather than filename
+        assert!(urls[1].ends_with(".png"));
+
+        for url in urls {
+            let file: ChatFile = url.parse()?;
+            assert!(state.store.exists(&file.key()).await?);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_file_should_reject_large_file() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let data = vec![b'a'; state.config.upload.max_file_size + 1];
+
+        let res = upload(&state, &[("large.txt", &data)]).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_file_should_reject_large_request() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let count = state.config.upload.max_request_size / state.config.upload.max_file_size + 1;
+        // each file is within file size limit, but not the request as a whole
+        let files: Vec<Vec<u8>> = (0..count)
+            .map(|i| {
+                let mut data = vec![b'a'; state.config.upload.max_file_size - 1];
+                data.push(i as u8);
+                data
+            })
+            .collect();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|d| ("large.txt", &d[..])).collect();
+
+        let res = upload(&state, &files).await?;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn upload_file_should_reject_unsupported_type() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let res = upload(&state, &[("index.html", b"<html></html>")]).await?;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        // denied even though image/* is allowed
+        let res = upload(&state, &[("logo.svg", b"<svg></svg>")]).await?;
+        assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        Ok(())
+    }
+
+    async fn prepare_file(state: &AppState, data: &'static [u8]) -> Result<ChatFile> {
+        let file = ChatFile::new(1, "test.txt", data);
+        state.store.put(&file.key(), data.into()).await?;
+        Ok(file)
+    }
+
+    async fn download(
+        state: &AppState,
+        file: &ChatFile,
+        size: Option<u32>,
+        if_none_match: Option<&str>,
+        range: Option<Range>,
+    ) -> Result<Response> {
+        let path = file.key();
+        let path = path.trim_start_matches(&format!("{}/", file.ws_id));
+        let if_none_match = if_none_match.map(|v| {
+            let etag: ETag = v.parse().unwrap();
+            TypedHeader(IfNoneMatch::from(etag))
+        });
+
+        let res = download_file_handler(
+            State(state.clone()),
+            Path((file.ws_id, path.to_string())),
+            Query(DownloadFile { size }),
+            if_none_match,
+            range.map(TypedHeader),
+        )
+        .await?;
+        Ok(res)
+    }
+
+    #[tokio::test]
+    async fn download_file_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let data = b"hello world, this is a chat file";
+        let file = prepare_file(&state, data).await?;
+
+        let res = download(&state, &file, None, None, None).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers().get(header::ETAG).unwrap().clone();
+        assert_eq!(etag, format!("\"{}\"", file.hash).as_str());
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+        assert_eq!(
+            res.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(),
+            "nosniff"
+        );
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], data);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn download_file_should_support_range() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let data = b"hello world, this is a chat file";
+        let file = prepare_file(&state, data).await?;
+
+        let res = download(&state, &file, None, None, Some(Range::bytes(6..11)?)).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(
+            res.headers().get(header::CONTENT_RANGE).unwrap(),
+            format!("bytes 6-10/{}", data.len()).as_str()
+        );
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"world");
+
+        // open-ended range
+        let res = download(&state, &file, None, None, Some(Range::bytes(0..)?)).await?;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], data);
+
+        let len = data.len() as u64;
+        let res = download(&state, &file, None, None, Some(Range::bytes(len..)?)).await?;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn download_file_should_support_etag() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let file = prepare_file(&state, b"hello etag").await?;
+        let etag = format!("\"{}\"", file.hash);
+
+        let res = download(&state, &file, None, Some(&etag), None).await?;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        let body = res.into_body().collect().await?.to_bytes();
+        assert!(body.is_empty());
+
+        let res = download(&state, &file, None, Some("\"other\""), None).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn download_file_should_serve_thumbnail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let img = image::DynamicImage::new_rgb8(512, 512);
+        let mut data = std::io::Cursor::new(Vec::new());
+        img.write_to(&mut data, image::ImageFormat::Png)?;
+        let data = data.into_inner();
+
+        let res = upload(&state, &[("image.png", &data)]).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = res.into_body().collect().await?.to_bytes();
+        let urls: Vec<String> = serde_json::from_slice(&body)?;
+        let file: ChatFile = urls[0].parse()?;
+
+        let res = download(&state, &file, Some(200), None, None).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::ETAG).unwrap(),
+            format!("\"{}-256\"", file.hash).as_str()
+        );
+        assert_eq!(
+            res.headers().get(header::CONTENT_TYPE).unwrap(),
+            "image/png"
+        );
+        let body = res.into_body().collect().await?.to_bytes();
+        let thumbnail = image::load_from_memory(&body)?;
+        assert_eq!(thumbnail.width(), 256);
+
+        // no thumbnail as large as 1024, fallback to original
+        let res = download(&state, &file, Some(1024), None, None).await?;
+        let body = res.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], &data[..]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn download_file_should_reject_invalid_path() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = download_file_handler(
+            State(state),
+            Path((1, "../../etc/passwd".to_string())),
+            Query(DownloadFile { size: None }),
+            None,
+            None,
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_should_be_modified_by_sender_or_admin() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state.find_chat_by_id(1).await?.expect("chat should exist");
+        let bob = state.find_user_by_email("bob@acme.org").await?.unwrap();
+        let alice = state.find_user_by_email("alice@acme.org").await?.unwrap();
+        let input = UpdateMessage {
+            content: "edited".to_string(),
+        };
+
+        // message 3 is sent by bob, message 1 by tchen
+        let res = update_msg_handler(
+            Extension(bob.clone()),
+            Extension(chat.clone()),
+            State(state.clone()),
+            Path((1, 3)),
+            Json(input.clone()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let ret = update_msg_handler(
+            Extension(bob.clone()),
+            Extension(chat.clone()),
+            State(state.clone()),
+            Path((1, 1)),
+            Json(input),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        let res = delete_msg_handler(
+            Extension(alice),
+            Extension(chat.clone()),
+            State(state.clone()),
+            Path((1, 1)),
+        )
+        .await?
+        .into_response();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        let ret =
+            delete_msg_handler(Extension(bob), Extension(chat), State(state), Path((1, 1))).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
 }
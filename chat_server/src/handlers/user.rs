@@ -1,19 +1,55 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Query, State},
     http::StatusCode,
     response::IntoResponse,
-    Json,
+    Extension, Json,
 };
+use chat_core::User;
 
-use crate::{AppError, AppState};
+use crate::{models::ListUsers, AppError, AppState};
 
-pub async fn get_user_list_handler(
-    Path(_ws_id): Path<String>,
+/// Users of the workspace the token is scoped to
+pub(crate) async fn get_user_list_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
+    Query(input): Query<ListUsers>,
 ) -> Result<impl IntoResponse, AppError> {
-    let ws_id: i32 = _ws_id
-        .parse()
-        .map_err(|_| AppError::NotFound("not found workspace".to_string()))?;
-    let users = state.fetch_all_users(ws_id).await?;
+    let users = state.fetch_all_chat_users(user.ws_id, &input).await?;
     Ok((StatusCode::OK, Json(users)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chat_core::ChatUser;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn get_user_list_handler_should_use_token_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+
+        let input: ListUsers = serde_json::from_str(r#"{"q": "d"}"#)?;
+        let ret =
+            get_user_list_handler(Extension(user.clone()), State(state.clone()), Query(input))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let users: Vec<ChatUser> = serde_json::from_slice(&body)?;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].email, "daisy@acme.org");
+
+        // token scoped to foo, where tchen is the only member
+        let user = User { ws_id: 2, ..user };
+        let input: ListUsers = serde_json::from_str("{}")?;
+        let ret = get_user_list_handler(Extension(user), State(state), Query(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let users: Vec<ChatUser> = serde_json::from_slice(&body)?;
+        assert_eq!(users.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,211 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use chat_core::User;
+
+use crate::{
+    models::{CreateInvite, JoinWorkspace, TransferOwner, UpdateMember},
+    AppError, AppState,
+};
+
+/// Workspaces the caller belongs to
+pub(crate) async fn list_workspaces_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.fetch_user_workspaces(user.id).await?;
+    Ok((StatusCode::OK, Json(workspaces)))
+}
+
+/// Join another workspace by invite, use switch to get tokens scoped to it
+pub(crate) async fn join_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<JoinWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws_id = state.add_to_workspace(user.id, &input.invite).await?;
+    let ws = state.find_by_id(ws_id).await?;
+    Ok((StatusCode::CREATED, Json(ws)))
+}
+
+/// Issue tokens scoped to the workspace, which is also used on next sign in
+pub(crate) async fn switch_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(ws_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let user = state.switch_workspace(user.id, ws_id).await?;
+    Ok((StatusCode::OK, Json(state.issue_tokens(user).await?)))
+}
+
+/// Create an invite to the workspace, any member could invite others
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(ws_id): Path<i64>,
+    Json(input): Json<CreateInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_workspace(&user, ws_id)?;
+    let invite = state.create_invite(ws_id, user.id, &input).await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+pub(crate) async fn update_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((ws_id, user_id)): Path<(i64, i64)>,
+    Json(input): Json<UpdateMember>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_workspace(&user, ws_id)?;
+    let member = state
+        .update_member_role(ws_id, user.id, user_id, input.role)
+        .await?;
+    Ok((StatusCode::OK, Json(member)))
+}
+
+pub(crate) async fn remove_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path((ws_id, user_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_workspace(&user, ws_id)?;
+    state.remove_member(ws_id, user.id, user_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn transfer_owner_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(ws_id): Path<i64>,
+    Json(input): Json<TransferOwner>,
+) -> Result<impl IntoResponse, AppError> {
+    verify_workspace(&user, ws_id)?;
+    let ws = state.transfer_owner(ws_id, user.id, input.user_id).await?;
+    Ok((StatusCode::OK, Json(ws)))
+}
+
+// token must be scoped to the workspace in path
+fn verify_workspace(user: &User, ws_id: i64) -> Result<(), AppError> {
+    if user.ws_id != ws_id {
+        return Err(AppError::NotWorkspaceMember(format!(
+            "user {} is not a member of workspace {}",
+            user.id, ws_id
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handlers::AuthOutput, models::WorkspaceInvite};
+    use anyhow::Result;
+    use chat_core::{Workspace, WorkspaceRole};
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn create_invite_handler_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+
+        let ret = create_invite_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(1),
+            Json(CreateInvite::new(1, 60)),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let invite: WorkspaceInvite = serde_json::from_slice(&body)?;
+        assert_eq!(invite.ws_id, 1);
+        assert!(!invite.token.is_empty());
+
+        let ret = create_invite_handler(
+            Extension(user),
+            State(state),
+            Path(2),
+            Json(CreateInvite::new(1, 60)),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceMember(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn member_handlers_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+
+        let ret = update_member_handler(
+            Extension(owner.clone()),
+            State(state.clone()),
+            Path((1, 3)),
+            Json(UpdateMember {
+                role: WorkspaceRole::Admin,
+            }),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let ret =
+            remove_member_handler(Extension(owner.clone()), State(state.clone()), Path((1, 4)))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = transfer_owner_handler(
+            Extension(owner.clone()),
+            State(state.clone()),
+            Path(1),
+            Json(TransferOwner { user_id: 3 }),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ws: Workspace = serde_json::from_slice(&body)?;
+        assert_eq!(ws.owner_id, 3);
+
+        // no longer the owner
+        let ret = transfer_owner_handler(
+            Extension(owner),
+            State(state),
+            Path(1),
+            Json(TransferOwner { user_id: 1 }),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switch_workspace_handler_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+
+        let ret = list_workspaces_handler(Extension(user.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let workspaces: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+        assert_eq!(workspaces.len(), 2);
+
+        let ret = switch_workspace_handler(Extension(user.clone()), State(state.clone()), Path(2))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let output: AuthOutput = serde_json::from_slice(&body)?;
+        assert_eq!(state.dk.verify(&output.token)?.ws_id, 2);
+
+        let ret = switch_workspace_handler(Extension(user), State(state), Path(3)).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceMember(_))));
+        Ok(())
+    }
+}
@@ -1,8 +1,11 @@
 mod config;
 mod error;
 mod handlers;
+mod middlewares;
 mod models;
+mod storage;
 
+use axum::extract::DefaultBodyLimit;
 use axum::middleware::from_fn_with_state;
 use axum::routing::{get, patch, post};
 use axum::Router;
@@ -10,14 +13,17 @@ use chat_core::middlewares::{set_layer, verify_token, TokenVerify};
 use chat_core::utils::{DecodingKey, EncodingKey};
 use core::fmt;
 use sqlx::PgPool;
-use tokio::fs;
+use storage::{new_store, FileStore};
 
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
 
 pub use config::*;
 pub use error::AppError;
 use handlers::*;
+use middlewares::verify_chat;
 
 #[derive(Debug, Clone)]
 pub(crate) struct AppState {
@@ -30,37 +36,99 @@ pub(crate) struct AppStateInner {
     pub(crate) dk: DecodingKey,
     pub(crate) ek: EncodingKey,
     pub(crate) pg_pool: PgPool,
+    pub(crate) store: Box<dyn FileStore>,
 }
 
 pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
     let state = AppState::try_new(config).await?;
+    spawn_purge_task(state.clone());
 
-    let api = Router::new()
-        .with_state(state.clone())
-        .route("/chats", get(list_chat_handler).post(create_chat_handler))
+    let chat = Router::new()
         .route(
-            "/chats/:id",
+            "/:id",
             patch(update_chat_handler).delete(delete_chat_handler),
         )
         .route(
-            "/chats/:id/messages",
+            "/:id/messages",
             get(list_msg_handler).post(send_msg_handler),
         )
-        .route("/files/upload", post(upload_file_handler))
+        .route(
+            "/:id/messages/:msg_id",
+            patch(update_msg_handler).delete(delete_msg_handler),
+        )
+        .route("/:id/messages/:msg_id/edits", get(list_msg_edits_handler))
+        .route("/:id/messages/:msg_id/replies", get(list_replies_handler))
+        .route(
+            "/:id/messages/:msg_id/reactions/:emoji",
+            post(add_reaction_handler).delete(remove_reaction_handler),
+        )
+        .route("/:id/leave", post(leave_chat_handler))
+        .route("/:id/read", post(mark_read_handler))
+        .route("/:id/archive", post(archive_chat_handler))
+        .route("/:id/unarchive", post(unarchive_chat_handler))
+        .layer(from_fn_with_state(state.clone(), verify_chat))
+        .route("/", get(list_chat_handler).post(create_chat_handler))
+        .route("/:id/join", post(join_chat_handler));
+
+    let api = Router::new()
+        .with_state(state.clone())
+        .nest("/chats", chat)
+        .route("/channels", get(list_channels_handler))
+        .route("/dm/:user_id", get(open_dm_handler))
+        .route(
+            "/files/upload",
+            post(upload_file_handler)
+                .layer(DefaultBodyLimit::max(state.config.upload.max_request_size)),
+        )
         .route("/files/:ws_id/*path", get(download_file_handler))
-        .route("/users/:ws_id", get(get_user_list_handler))
+        .route("/users", get(get_user_list_handler))
+        .route("/workspaces", get(list_workspaces_handler))
+        .route("/workspaces/join", post(join_workspace_handler))
+        .route("/workspaces/:id/switch", post(switch_workspace_handler))
+        .route("/workspaces/:id/invites", post(create_invite_handler))
+        .route(
+            "/workspaces/:id/members/:user_id",
+            patch(update_member_handler).delete(remove_member_handler),
+        )
+        .route("/workspaces/:id/owner", post(transfer_owner_handler))
+        .route("/signout", post(signout_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .route("/signup", post(signup_handler))
+        .route("/refresh", post(refresh_handler));
 
     let router = Router::new()
         .route("/", get(index_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
         .nest("/api", api)
         .with_state(state);
 
     Ok(set_layer(router))
 }
 
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// deleted chats are kept for the retention window, then purged in background
+// along with revoked tokens which have expired
+fn spawn_purge_task(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.purge_deleted_chats().await {
+                Ok(0) => {}
+                Ok(n) => info!("purged {} deleted chats", n),
+                Err(e) => warn!("purge deleted chats failed: {}", e),
+            }
+            match state.purge_revoked_tokens().await {
+                Ok(0) => {}
+                Ok(n) => info!("purged {} expired revoked tokens", n),
+                Err(e) => warn!("purge revoked tokens failed: {}", e),
+            }
+        }
+    });
+}
+
 // state.config => state.inner.config
 impl Deref for AppState {
     type Target = AppStateInner;
@@ -72,9 +140,13 @@ impl Deref for AppState {
 
 impl AppState {
     pub async fn try_new(config: AppConfig) -> Result<Self, AppError> {
-        fs::create_dir_all(&config.server.base_dir).await?;
-        let dk = DecodingKey::load(&config.auth.pk).expect("load pk failed");
-        let ek = EncodingKey::load(&config.auth.sk).expect("load sk failed");
+        let store = new_store(&config.storage)?;
+        let dk = DecodingKey::load_keyset(config.auth.verification_keys())
+            .expect("load pk failed")
+            .with_config(config.auth.jwt.clone());
+        let ek = EncodingKey::load(&config.auth.sk)
+            .expect("load sk failed")
+            .with_config(config.auth.jwt.clone());
         let pg_pool = PgPool::connect(&config.server.db_url)
             .await
             .expect("connect to db failed");
@@ -84,6 +156,7 @@ impl AppState {
                 dk,
                 ek,
                 pg_pool,
+                store,
             }),
         })
     }
@@ -99,8 +172,15 @@ impl fmt::Debug for AppStateInner {
 
 impl TokenVerify for AppState {
     type Error = AppError;
-    fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
+        let claims = self.dk.verify_claims(token)?;
+        let jti = claims
+            .jwt_id
+            .ok_or_else(|| AppError::InvalidToken("token without id".to_string()))?;
+        if self.is_token_revoked(&jti).await? {
+            return Err(AppError::InvalidToken(format!("token {} is revoked", jti)));
+        }
+        Ok(claims.custom)
     }
 }
 
@@ -114,17 +194,23 @@ mod test_util {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
             let config = AppConfig::load()?;
 
-            let dk = DecodingKey::load(&config.auth.pk).expect("load pk failed");
-            let ek = EncodingKey::load(&config.auth.sk).expect("load sk failed");
+            let dk = DecodingKey::load_keyset(config.auth.verification_keys())
+                .expect("load pk failed")
+                .with_config(config.auth.jwt.clone());
+            let ek = EncodingKey::load(&config.auth.sk)
+                .expect("load sk failed")
+                .with_config(config.auth.jwt.clone());
             let post = config.server.db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[..post];
             let (tdb, pg_pool) = get_test_pool(Some(server_url)).await;
+            let store = new_store(&config.storage)?;
             let state = Self {
                 inner: Arc::new(AppStateInner {
                     config,
                     ek,
                     dk,
                     pg_pool,
+                    store,
                 }),
             };
             Ok((tdb, state))
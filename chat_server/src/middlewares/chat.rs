@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{FromRequestParts, Path, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chat_core::User;
+
+use crate::{AppError, AppState};
+
+/// Load the chat in `/chats/:id/*` and make sure the current user is a member of it.
+/// The loaded chat is inserted into request extensions for downstream handlers.
+pub(crate) async fn verify_chat(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+
+    let chat_id =
+        match Path::<HashMap<String, String>>::from_request_parts(&mut parts, &state).await {
+            Ok(Path(params)) => params.get("id").and_then(|id| id.parse::<i64>().ok()),
+            Err(_) => None,
+        };
+    let Some(chat_id) = chat_id else {
+        return AppError::NotFound("invalid chat id".to_string()).into_response();
+    };
+
+    let Some(user) = parts.extensions.get::<User>() else {
+        return AppError::NotChatMember("user not found in request".to_string()).into_response();
+    };
+
+    let chat = match state.find_chat_by_id(chat_id).await {
+        Ok(Some(chat)) => chat,
+        Ok(None) => {
+            return AppError::NotFound(format!("chat {} not found", chat_id)).into_response()
+        }
+        Err(e) => return e.into_response(),
+    };
+
+    if chat.ws_id != user.ws_id || !chat.members.contains(&user.id) {
+        return AppError::NotChatMember(format!(
+            "user {} is not a member of chat {}",
+            user.id, chat_id
+        ))
+        .into_response();
+    }
+
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(chat);
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUser;
+    use anyhow::Result;
+    use axum::{
+        body::Body, http::StatusCode, middleware::from_fn_with_state, routing::get, Router,
+    };
+    use chat_core::middlewares::verify_token;
+    use tower::ServiceExt;
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "OK")
+    }
+
+    fn get_request(uri: &str, token: &str) -> Result<Request> {
+        let req = Request::builder()
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())?;
+        Ok(req)
+    }
+
+    #[tokio::test]
+    async fn verify_chat_middleware_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let app = Router::new()
+            .route("/chats/:id", get(handler))
+            .route("/chats/:id/messages", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_chat))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state.clone());
+
+        // member of private channel
+        let user = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+        let token = state.ek.sign(user)?;
+        let res = app
+            .clone()
+            .oneshot(get_request("/chats/2", &token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app
+            .clone()
+            .oneshot(get_request("/chats/2/messages", &token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // chat not exists
+        let res = app
+            .clone()
+            .oneshot(get_request("/chats/100/messages", &token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // same workspace but not a member of private channel
+        let user = state.find_user_by_email("daisy@acme.org").await?.unwrap();
+        let token = state.ek.sign(user)?;
+        let res = app
+            .clone()
+            .oneshot(get_request("/chats/2/messages", &token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // member id but token scoped to another workspace
+        let user = state.find_user_by_email("tchen@acme.org").await?.unwrap();
+        let token = state.ek.sign(User { ws_id: 2, ..user })?;
+        let res = app
+            .clone()
+            .oneshot(get_request("/chats/1", &token)?)
+            .await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // user from another workspace
+        let input = CreateUser::new("newco", "Foo Chen", "foo@newco.org", "123456");
+        let user = state.create_user(&input).await?;
+        let token = state.ek.sign(user)?;
+        let res = app.oneshot(get_request("/chats/1", &token)?).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        Ok(())
+    }
+}
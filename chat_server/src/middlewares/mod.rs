@@ -0,0 +1,3 @@
+mod chat;
+
+pub(crate) use chat::verify_chat;
@@ -1,8 +1,10 @@
-use chat_core::{Chat, ChatType, ChatUser};
+use chat_core::{Chat, ChatType, ChatUser, User};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
-use tracing::info;
+use tracing::{info, warn};
 
+use super::ChatFile;
 use crate::{AppError, AppState};
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, Default)]
@@ -12,11 +14,32 @@ pub struct CreateChat {
     pub public: bool,
 }
 
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize, Default)]
+/// Partial update of a chat, fields not provided are left unchanged
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct UpdateChat {
+    // empty name removes the name
     pub name: Option<String>,
-    pub members: Vec<i64>,
-    pub public: bool,
+    #[serde(default)]
+    pub add_members: Vec<i64>,
+    #[serde(default)]
+    pub remove_members: Vec<i64>,
+    pub public: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListChats {
+    // archived chats are hidden unless asked for
+    #[serde(default)]
+    pub archived: bool,
+}
+
+// public channel listed for discovery, members are not exposed
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct PublicChannel {
+    pub id: i64,
+    pub name: Option<String>,
+    pub member_count: i64,
+    pub created_at: DateTime<Utc>,
 }
 
 #[allow(unused)]
@@ -28,42 +51,48 @@ impl AppState {
             .collect::<Vec<_>>()
             .join(",")
     }
-    pub async fn create_chat(&self, input: CreateChat, ws_id: i64) -> Result<Chat, AppError> {
-        let users = self.fetch_chat_user_by_ids(&input.members).await?;
-        let len = users.len();
-
-        let chat_type = match (&input.name, len) {
-            (_, 0..=1) => {
-                return Err(AppError::CreateChatError(
-                    "At least 2 members are required".to_string(),
-                ))
-            }
-            (None, 9..) => {
-                return Err(AppError::CreateChatError(
-                    "Name is required when members are more than 8".to_string(),
-                ))
+    /// Create a chat in workspace `ws_id` by `creator_id`, who is always a member.
+    /// Duplicated members are removed and all members must belong to the workspace.
+    pub async fn create_chat(
+        &self,
+        mut input: CreateChat,
+        ws_id: i64,
+        creator_id: i64,
+    ) -> Result<Chat, AppError> {
+        let mut members = vec![creator_id];
+        for id in input.members {
+            if !members.contains(&id) {
+                members.push(id);
             }
-            (None, 2) => ChatType::Single,
-            (None, 3..=8) => ChatType::Group,
-            (Some(_), _) => {
-                if input.public {
-                    ChatType::PublicChannel
-                } else {
-                    ChatType::PrivateChannel
-                }
-            }
-        };
+        }
+        input.members = members;
+
+        self.verify_workspace_members(ws_id, &input.members, AppError::CreateChatError)
+            .await?;
+        let chat_type = get_chat_type(input.name.as_deref(), input.members.len(), input.public)
+            .map_err(AppError::CreateChatError)?;
 
         info!("chat_type: {:?}", chat_type);
         info!("members: {:?}", input.members);
         info!("ws_id: {:?}", ws_id);
         info!("name: {:?}", input.name);
 
-        let chat = sqlx::query_as(
+        // there's at most one direct chat between two users, reuse it
+        let direct = match (&chat_type, input.members.as_slice()) {
+            (ChatType::Single, &[a, b]) => Some((a, b)),
+            _ => None,
+        };
+        if let Some((a, b)) = direct {
+            if let Some(chat) = self.find_direct_chat(ws_id, a, b).await? {
+                return Ok(chat);
+            }
+        }
+
+        let ret = sqlx::query_as(
             r#"
             INSERT INTO chats (ws_id, name, type, members)
             VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            RETURNING id, ws_id, name, type, members, archived_at, created_at
             "#,
         )
         .bind(ws_id)
@@ -71,49 +100,348 @@ impl AppState {
         .bind(chat_type)
         .bind(&input.members)
         .fetch_one(&self.pg_pool)
-        .await?;
+        .await;
 
-        Ok(chat)
+        match (ret, direct) {
+            // created concurrently by the other user
+            (Err(sqlx::Error::Database(e)), Some((a, b))) if e.is_unique_violation() => self
+                .find_direct_chat(ws_id, a, b)
+                .await?
+                .ok_or_else(|| AppError::CreateChatError("direct chat conflict".to_string())),
+            (ret, _) => Ok(ret?),
+        }
     }
 
-    pub async fn fetch_chats(&self, ws_id: i64) -> Result<Vec<Chat>, AppError> {
+    /// Chats of workspace `user_id` is a member of, with its unread counts
+    pub async fn fetch_chats(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+        input: &ListChats,
+    ) -> Result<Vec<Chat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT * FROM chats
-            WHERE ws_id = $1
+            SELECT c.id, c.ws_id, c.name, c.type, c.members, c.archived_at, c.created_at,
+                (SELECT count(*) FROM messages m
+                 WHERE m.chat_id = c.id AND m.id > COALESCE(r.last_read_message_id, 0)
+                 AND m.sender_id <> $3 AND m.deleted_at IS NULL
+                 AND m.thread_root_id IS NULL) AS unread_count
+            FROM chats c
+            LEFT JOIN chat_reads r ON r.chat_id = c.id AND r.user_id = $3
+            WHERE c.ws_id = $1 AND $3 = ANY(c.members)
+            AND c.deleted_at IS NULL AND ($2 OR c.archived_at IS NULL)
+            ORDER BY c.id
             "#,
         )
         .bind(ws_id)
+        .bind(input.archived)
+        .bind(user_id)
         .fetch_all(&self.pg_pool)
         .await?;
 
         Ok(chats)
     }
 
-    pub async fn update_chat_by_id(&self, id: i64, input: UpdateChat) -> Result<Chat, AppError> {
+    pub async fn find_direct_chat(
+        &self,
+        ws_id: i64,
+        a: i64,
+        b: i64,
+    ) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, archived_at, created_at
+            FROM chats
+            WHERE ws_id = $1 AND type = 'single' AND deleted_at IS NULL
+            AND LEAST(members[1], members[2]) = LEAST($2, $3)
+            AND GREATEST(members[1], members[2]) = GREATEST($2, $3)
+            "#,
+        )
+        .bind(ws_id)
+        .bind(a)
+        .bind(b)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// Direct chat between user and other member of the workspace, created if missing
+    pub async fn open_direct_chat(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+        other_id: i64,
+    ) -> Result<Chat, AppError> {
+        if user_id == other_id {
+            return Err(AppError::CreateChatError(
+                "could not chat with yourself".to_string(),
+            ));
+        }
+        self.verify_workspace_members(ws_id, &[other_id], AppError::CreateChatError)
+            .await?;
+
+        let input = CreateChat {
+            name: None,
+            members: vec![other_id],
+            public: false,
+        };
+        self.create_chat(input, ws_id, user_id).await
+    }
+
+    pub async fn fetch_public_channels(&self, ws_id: i64) -> Result<Vec<PublicChannel>, AppError> {
+        let channels = sqlx::query_as(
+            r#"
+            SELECT id, name, cardinality(members)::BIGINT AS member_count, created_at
+            FROM chats
+            WHERE ws_id = $1 AND type = 'public_channel'
+            AND archived_at IS NULL AND deleted_at IS NULL
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pg_pool)
+        .await?;
+
+        Ok(channels)
+    }
+
+    /// Join a public channel of user's workspace, joining twice is a no-op
+    pub async fn join_chat(&self, id: i64, user: &User) -> Result<Chat, AppError> {
+        let chat = match self.find_chat_by_id(id).await? {
+            Some(chat) if chat.ws_id == user.ws_id => chat,
+            _ => return Err(AppError::NotFound(format!("chat {}", id))),
+        };
+        let not_joinable =
+            || AppError::PermissionDenied(format!("chat {} is not an active public channel", id));
+        if chat.r#type != ChatType::PublicChannel || chat.archived_at.is_some() {
+            return Err(not_joinable());
+        }
+
+        // the chat might have changed since it was read
+        let chat: Option<Chat> = sqlx::query_as(
             r#"
             UPDATE chats
-            SET name = $1, members = $2, public = $3
-            WHERE id = $3
-            RETURNING id, ws_id, name, type, members, created_at
+            SET members = array_append(members, $2)
+            WHERE id = $1 AND NOT ($2 = ANY(members))
+            AND type = 'public_channel' AND archived_at IS NULL AND deleted_at IS NULL
+            RETURNING id, ws_id, name, type, members, archived_at, created_at
             "#,
         )
-        .bind(input.name)
-        .bind(input.members)
-        .bind(input.public)
         .bind(id)
-        .fetch_one(&self.pg_pool)
+        .bind(user.id)
+        .fetch_optional(&self.pg_pool)
         .await?;
 
+        match chat {
+            Some(chat) => Ok(chat),
+            None => match self.find_chat_by_id(id).await? {
+                Some(chat) if chat.members.contains(&user.id) => Ok(chat),
+                Some(_) => Err(not_joinable()),
+                None => Err(AppError::NotFound(format!("chat {}", id))),
+            },
+        }
+    }
+
+    /// Leave a channel, other chats could not be left since their type
+    /// depends on members. Archived channels could not be left either.
+    pub async fn leave_chat(&self, chat: &Chat, user_id: i64) -> Result<(), AppError> {
+        if !matches!(
+            chat.r#type,
+            ChatType::PublicChannel | ChatType::PrivateChannel
+        ) {
+            return Err(AppError::UpdateChatError(format!(
+                "chat {} is not a channel",
+                chat.id
+            )));
+        }
+        let archived = || AppError::UpdateChatError(format!("chat {} is archived", chat.id));
+        if chat.archived_at.is_some() {
+            return Err(archived());
+        }
+
+        let ret = sqlx::query(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $2)
+            WHERE id = $1 AND $2 = ANY(members)
+            AND archived_at IS NULL AND deleted_at IS NULL
+            "#,
+        )
+        .bind(chat.id)
+        .bind(user_id)
+        .execute(&self.pg_pool)
+        .await?;
+
+        // either not a member, or archived or deleted since it was read
+        if ret.rows_affected() == 0 {
+            match self.find_chat_by_id(chat.id).await? {
+                Some(chat) if chat.archived_at.is_some() => return Err(archived()),
+                Some(_) => {}
+                None => return Err(AppError::NotFound(format!("chat {}", chat.id))),
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn find_chat_by_id(&self, id: i64) -> Result<Option<Chat>, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, archived_at, created_at
+            FROM chats
+            WHERE id = $1 AND deleted_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        Ok(chat)
+    }
+
+    /// Apply partial changes to chat, its type is derived again by the same
+    /// rules as `create_chat`. New members must belong to the workspace.
+    pub async fn update_chat_by_id(&self, id: i64, input: UpdateChat) -> Result<Chat, AppError> {
+        let mut tx = self.pg_pool.begin().await?;
+        let chat: Chat = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, archived_at, created_at
+            FROM chats
+            WHERE id = $1 AND deleted_at IS NULL
+            FOR UPDATE
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("chat {}", id)))?;
+        if chat.archived_at.is_some() {
+            return Err(AppError::UpdateChatError(format!(
+                "chat {} is archived",
+                id
+            )));
+        }
+
+        let name = match input.name {
+            Some(name) if name.trim().is_empty() => None,
+            Some(name) => Some(name.trim().to_string()),
+            None => chat.name,
+        };
+        let public = input
+            .public
+            .unwrap_or(chat.r#type == ChatType::PublicChannel);
+
+        let mut members = chat.members;
+        let mut added = vec![];
+        for id in input.add_members {
+            if !members.contains(&id) && !added.contains(&id) {
+                added.push(id);
+            }
+        }
+        self.verify_workspace_members(chat.ws_id, &added, AppError::UpdateChatError)
+            .await?;
+        members.extend(added);
+        members.retain(|id| !input.remove_members.contains(id));
+
+        let chat_type = get_chat_type(name.as_deref(), members.len(), public)
+            .map_err(AppError::UpdateChatError)?;
+
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET name = $1, type = $2, members = $3
+            WHERE id = $4
+            RETURNING id, ws_id, name, type, members, archived_at, created_at
+            "#,
+        )
+        .bind(name)
+        .bind(chat_type)
+        .bind(members)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                AppError::UpdateChatError("direct chat already exists".to_string())
+            }
+            e => e.into(),
+        })?;
+        tx.commit().await?;
+
         Ok(chat)
     }
 
+    // unknown users and users of other workspaces are reported by `invalid`
+    async fn verify_workspace_members(
+        &self,
+        ws_id: i64,
+        ids: &[i64],
+        invalid: fn(String) -> AppError,
+    ) -> Result<(), AppError> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let found: Vec<(i64, bool)> = sqlx::query_as(
+            r#"
+            SELECT u.id, m.user_id IS NOT NULL
+            FROM users u
+            LEFT JOIN workspace_members m ON m.user_id = u.id AND m.ws_id = $1
+            WHERE u.id = ANY($2)
+            "#,
+        )
+        .bind(ws_id)
+        .bind(ids)
+        .fetch_all(&self.pg_pool)
+        .await?;
+
+        let unknown: Vec<_> = ids
+            .iter()
+            .filter(|id| !found.iter().any(|(found, _)| found == *id))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(invalid(format!("users {:?} do not exist", unknown)));
+        }
+
+        let foreign: Vec<_> = found
+            .iter()
+            .filter(|(_, is_member)| !is_member)
+            .map(|(id, _)| id)
+            .collect();
+        if !foreign.is_empty() {
+            return Err(invalid(format!(
+                "users {:?} are not members of workspace {}",
+                foreign, ws_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Archive or unarchive a chat, archiving twice keeps the first timestamp
+    pub async fn archive_chat(&self, id: i64, archived: bool) -> Result<Chat, AppError> {
+        let chat = sqlx::query_as(
+            r#"
+            UPDATE chats
+            SET archived_at = CASE WHEN $2 THEN COALESCE(archived_at, NOW()) END
+            WHERE id = $1 AND deleted_at IS NULL
+            RETURNING id, ws_id, name, type, members, archived_at, created_at
+            "#,
+        )
+        .bind(id)
+        .bind(archived)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        chat.ok_or_else(|| AppError::NotFound(format!("chat {}", id)))
+    }
+
+    /// Soft delete a chat, it's purged with its messages after the retention window
     pub async fn delete_chat_by_id(&self, id: i64) -> Result<(), AppError> {
         sqlx::query(
             r#"
-            DELETE FROM chats
-            WHERE id = $1
+            UPDATE chats
+            SET deleted_at = NOW()
+            WHERE id = $1 AND deleted_at IS NULL
             "#,
         )
         .bind(id)
@@ -122,6 +450,93 @@ impl AppState {
 
         Ok(())
     }
+
+    /// Remove chats deleted more than `chat_retention_days` ago, returns the number purged.
+    /// Files are shared by content, so only those no longer attached anywhere are removed.
+    pub async fn purge_deleted_chats(&self) -> Result<u64, AppError> {
+        let mut tx = self.pg_pool.begin().await?;
+        let ids: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT id FROM chats
+            WHERE deleted_at < NOW() - make_interval(days => $1)
+            FOR UPDATE
+            "#,
+        )
+        .bind(self.config.server.chat_retention_days as i32)
+        .fetch_all(&mut *tx)
+        .await?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        // attachments of the messages, including their previous versions
+        let files: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT f FROM (
+                SELECT unnest(m.files) AS f FROM messages m WHERE m.chat_id = ANY($1)
+                UNION ALL
+                SELECT unnest(e.files) FROM message_edits e
+                JOIN messages m ON m.id = e.message_id
+                WHERE m.chat_id = ANY($1)
+            ) t
+            "#,
+        )
+        .bind(&ids)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        // messages and their children are removed by cascade
+        let ret = sqlx::query("DELETE FROM chats WHERE id = ANY($1)")
+            .bind(&ids)
+            .execute(&mut *tx)
+            .await?;
+
+        let in_use: Vec<String> = sqlx::query_scalar(
+            r#"
+            SELECT DISTINCT f FROM (
+                SELECT unnest(files) AS f FROM messages
+                UNION ALL
+                SELECT unnest(files) FROM message_edits
+            ) t
+            WHERE f = ANY($1)
+            "#,
+        )
+        .bind(&files)
+        .fetch_all(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        for url in files.iter().filter(|f| !in_use.contains(f)) {
+            if let Err(e) = self.delete_file(url).await {
+                warn!("delete file {} of purged chat failed: {}", url, e);
+            }
+        }
+
+        Ok(ret.rows_affected())
+    }
+
+    // remove file and its thumbnails from the store
+    async fn delete_file(&self, url: &str) -> Result<(), AppError> {
+        let file: ChatFile = url.parse()?;
+        if file.is_image() {
+            for size in &self.config.upload.thumbnail_sizes {
+                self.store.delete(&file.thumbnail_key(*size)).await?;
+            }
+        }
+        self.store.delete(&file.key()).await
+    }
+}
+
+// single and group chats are unnamed, channels are named
+fn get_chat_type(name: Option<&str>, len: usize, public: bool) -> Result<ChatType, String> {
+    match (name, len) {
+        (_, 0..=1) => Err("At least 2 members are required".to_string()),
+        (None, 9..) => Err("Name is required when members are more than 8".to_string()),
+        (None, 2) => Ok(ChatType::Single),
+        (None, 3..=8) => Ok(ChatType::Group),
+        (Some(_), _) if public => Ok(ChatType::PublicChannel),
+        (Some(_), _) => Ok(ChatType::PrivateChannel),
+    }
 }
 
 #[cfg(test)]
@@ -143,6 +558,7 @@ impl CreateChat {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::CreateUser;
 
     #[tokio::test]
     async fn create_should_work() {
@@ -150,18 +566,319 @@ mod tests {
 
         let input = CreateChat::new("", &[1, 2], false);
         let chat = state
-            .create_chat(input, 1)
+            .create_chat(input, 1, 1)
             .await
             .expect("chat create failed");
         assert_eq!(chat.members.len(), 2);
         assert_eq!(chat.r#type, ChatType::Single);
     }
 
+    #[tokio::test]
+    async fn create_should_validate_members() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // creator is included and duplicates are removed
+        let input = CreateChat::new("", &[3, 4, 3, 4], false);
+        let chat = state.create_chat(input, 1, 5).await?;
+        assert_eq!(chat.members, vec![5, 3, 4]);
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        let input = CreateChat::new("", &[2, 100], false);
+        let ret = state.create_chat(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(ref e)) if e.contains("do not exist")));
+
+        let other = CreateUser::new("newco", "Other", "other@newco.org", "123456");
+        let other = state.create_user(&other).await?;
+        let input = CreateChat::new("", &[2, other.id], false);
+        let ret = state.create_chat(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(ref e)) if e.contains("not members")));
+
+        // creator must be in the workspace as well
+        let input = CreateChat::new("", &[2, 3], false);
+        let ret = state.create_chat(input, 1, other.id).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn single_chat_should_be_deduplicated() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // chat 3 is the direct chat of 1 and 2
+        let chat = state
+            .create_chat(CreateChat::new("", &[2, 1], false), 1, 1)
+            .await?;
+        assert_eq!(chat.id, 3);
+
+        let chat = state.open_direct_chat(1, 3, 4).await?;
+        assert_eq!(chat.r#type, ChatType::Single);
+        let same = state.open_direct_chat(1, 4, 3).await?;
+        assert_eq!(chat.id, same.id);
+
+        let ret = state.open_direct_chat(1, 3, 3).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        let ret = state.open_direct_chat(2, 1, 3).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+
+        // group chat could not become a duplicated direct chat
+        let input = UpdateChat {
+            add_members: vec![2],
+            ..Default::default()
+        };
+        state.update_chat_by_id(4, input).await?;
+        let input = UpdateChat {
+            remove_members: vec![3, 4],
+            ..Default::default()
+        };
+        let ret = state.update_chat_by_id(4, input).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn fetch_all_should_work() {
         let (_tdb, state) = AppState::new_for_test().await.unwrap();
-        let chats = state.fetch_chats(1).await.expect("fetch all chats failed");
+        let chats = state
+            .fetch_chats(1, 1, &ListChats::default())
+            .await
+            .expect("fetch all chats failed");
 
         assert_eq!(chats.len(), 4);
+
+        // chats of others are not listed, daisy is only in the general channel
+        let chats = state
+            .fetch_chats(1, 5, &ListChats::default())
+            .await
+            .expect("fetch all chats failed");
+        assert_eq!(chats.iter().map(|c| c.id).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn find_by_id_should_work() {
+        let (_tdb, state) = AppState::new_for_test().await.unwrap();
+        let chat = state
+            .find_chat_by_id(1)
+            .await
+            .expect("find chat failed")
+            .unwrap();
+
+        assert_eq!(chat.name, Some("general".to_string()));
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+        assert!(state.find_chat_by_id(100).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn update_should_apply_partial_changes() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // rename only
+        let input = UpdateChat {
+            name: Some("secret".to_string()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(2, input).await?;
+        assert_eq!(chat.name.as_deref(), Some("secret"));
+        assert_eq!(chat.members, vec![1, 2, 3]);
+        assert_eq!(chat.r#type, ChatType::PrivateChannel);
+
+        // add and remove members, toggle public
+        let input = UpdateChat {
+            add_members: vec![4, 5, 1],
+            remove_members: vec![2],
+            public: Some(true),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(2, input).await?;
+        assert_eq!(chat.members, vec![1, 3, 4, 5]);
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+
+        // single chat becomes a group once a member is added
+        let input = UpdateChat {
+            add_members: vec![3],
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(3, input).await?;
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        // removing the name turns a small channel into a group
+        let input = UpdateChat {
+            name: Some("".to_string()),
+            ..Default::default()
+        };
+        let chat = state.update_chat_by_id(2, input).await?;
+        assert_eq!(chat.name, None);
+        assert_eq!(chat.r#type, ChatType::Group);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_should_validate_members() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let other = CreateUser::new("newco", "Other", "other@newco.org", "123456");
+        let other = state.create_user(&other).await?;
+
+        let input = UpdateChat {
+            add_members: vec![other.id],
+            ..Default::default()
+        };
+        let ret = state.update_chat_by_id(1, input).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+
+        let input = UpdateChat {
+            remove_members: vec![2],
+            ..Default::default()
+        };
+        let ret = state.update_chat_by_id(3, input).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+
+        let ret = state.update_chat_by_id(100, UpdateChat::default()).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn public_channels_should_be_joined_and_left() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let channels = state.fetch_public_channels(1).await?;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].name.as_deref(), Some("general"));
+        assert_eq!(channels[0].member_count, 5);
+
+        let input = CreateUser::with_invite(
+            &state
+                .create_invite(1, 1, &crate::models::CreateInvite::new(1, 60))
+                .await?
+                .token,
+            "New",
+            "new@acme.org",
+            "123456",
+        );
+        let user = state.create_user(&input).await?;
+        let chat = state.join_chat(1, &user).await?;
+        assert!(chat.members.contains(&user.id));
+        // joining again is a no-op
+        let chat = state.join_chat(1, &user).await?;
+        assert_eq!(chat.members.len(), 6);
+
+        let ret = state.join_chat(2, &user).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let other = User {
+            ws_id: 2,
+            ..user.clone()
+        };
+        let ret = state.join_chat(1, &other).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // archived after the chat was read
+        state.archive_chat(1, true).await?;
+        let ret = state.leave_chat(&chat, user.id).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let archived = state.find_chat_by_id(1).await?.unwrap();
+        assert!(archived.members.contains(&user.id));
+        let ret = state.leave_chat(&archived, user.id).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let ret = state.join_chat(1, &user).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        state.archive_chat(1, false).await?;
+
+        state.leave_chat(&chat, user.id).await?;
+        let chat = state.find_chat_by_id(1).await?.unwrap();
+        assert!(!chat.members.contains(&user.id));
+
+        let group = state.find_chat_by_id(4).await?.unwrap();
+        let ret = state.leave_chat(&group, 1).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archived_chat_should_be_hidden_and_read_only() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let chat = state.archive_chat(1, true).await?;
+        assert!(chat.archived_at.is_some());
+
+        let chats = state.fetch_chats(1, 1, &ListChats::default()).await?;
+        assert!(chats.iter().all(|c| c.id != 1));
+        let chats = state
+            .fetch_chats(1, 1, &ListChats { archived: true })
+            .await?;
+        assert_eq!(chats.len(), 4);
+        assert!(state.fetch_public_channels(1).await?.is_empty());
+
+        // still readable, but no new messages
+        assert!(state.find_chat_by_id(1).await?.is_some());
+        let user = state.find_user_by_id(1).await?.unwrap();
+        let input = crate::models::CreateMessage::new("hello", &[]);
+        let ret = state.create_message(&input, 1, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        let update = UpdateChat {
+            name: Some("renamed".to_string()),
+            ..Default::default()
+        };
+        let ret = state.update_chat_by_id(1, update).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+
+        let chat = state.archive_chat(1, false).await?;
+        assert!(chat.archived_at.is_none());
+        state.create_message(&input, 1, &user).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_chat_should_be_purged_after_retention() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.delete_chat_by_id(1).await?;
+        assert!(state.find_chat_by_id(1).await?.is_none());
+        let chats = state
+            .fetch_chats(1, 1, &ListChats { archived: true })
+            .await?;
+        assert_eq!(chats.len(), 3);
+        let ret = state.archive_chat(1, true).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // within the retention window
+        assert_eq!(state.purge_deleted_chats().await?, 0);
+
+        // files still attached in other chats are kept
+        let shared = ChatFile::new(1, "shared.txt", b"shared");
+        let own = ChatFile::new(1, "own.txt", b"own");
+        for file in [&shared, &own] {
+            state
+                .store
+                .put(&file.key(), file.hash.clone().into())
+                .await?;
+        }
+        sqlx::query("UPDATE messages SET files = $1 WHERE id = 1")
+            .bind(vec![shared.url(), own.url()])
+            .execute(&state.pg_pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO messages (chat_id, sender_id, content, files) VALUES (2, 1, 'hi', $1)",
+        )
+        .bind(vec![shared.url()])
+        .execute(&state.pg_pool)
+        .await?;
+
+        sqlx::query("UPDATE chats SET deleted_at = NOW() - INTERVAL '31 days' WHERE id = 1")
+            .execute(&state.pg_pool)
+            .await?;
+        assert_eq!(state.purge_deleted_chats().await?, 1);
+        let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM messages WHERE chat_id = 1")
+            .fetch_one(&state.pg_pool)
+            .await?;
+        assert_eq!(count, 0);
+        assert!(state.store.exists(&shared.key()).await?);
+        assert!(!state.store.exists(&own.key()).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_direct_chat_should_be_reopened() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.delete_chat_by_id(3).await?;
+        let chat = state.open_direct_chat(1, 1, 2).await?;
+        assert_ne!(chat.id, 3);
+        Ok(())
     }
 }
@@ -1,33 +1,208 @@
-use std::path::{Path, PathBuf};
+use std::{io::Cursor, str::FromStr};
 
 use super::ChatFile;
+use crate::{AppError, AppState};
 
+use axum::body::Bytes;
+use image::{DynamicImage, ImageFormat};
+use mime_guess::Mime;
 use sha1::{Digest, Sha1};
+use tokio::task;
+
+// extension for files we can neither sniff nor get from filename
+const DEFAULT_EXT: &str = "bin";
+// plain text has no magic bytes, these extensions are trusted for utf-8 content,
+// others like html or svg could be served as active content so they are not
+const TEXT_EXTS: &[&str] = &["txt", "md", "csv", "log"];
 
 impl ChatFile {
     pub fn new(ws_id: i64, filename: &str, data: &[u8]) -> Self {
         let hash = Sha1::digest(data);
         Self {
             ws_id,
-            ext: filename.split('.').last().unwrap_or("txt").to_string(),
+            ext: sniff_ext(filename, data),
             hash: format!("{:x}", hash),
         }
     }
 
-    pub fn url(&self) -> String {
-        format!("/files/{}", self.hash_to_path())
+    pub fn mime(&self) -> Mime {
+        mime_guess::from_ext(&self.ext).first_or_octet_stream()
     }
 
-    pub fn path(&self, base_dir: &Path) -> PathBuf {
-        base_dir.join(self.hash_to_path())
+    pub fn url(&self) -> String {
+        format!("/files/{}", self.key())
     }
 
-    // split hash into 3 parts, first 2 with 3 chars
-    fn hash_to_path(&self) -> String {
+    // key in file store: split hash into 3 parts, first 2 with 3 chars
+    pub fn key(&self) -> String {
         let (part1, part2) = self.hash.split_at(3);
         let (part2, part3) = part2.split_at(3);
         format!("{}/{}/{}/{}.{}", self.ws_id, part1, part2, part3, self.ext)
     }
+
+    // thumbnail is stored next to the original: {ws_id}/{part1}/{part2}/{part3}_{size}.{ext}
+    pub fn thumbnail_key(&self, size: u32) -> String {
+        let key = self.key();
+        let (path, _) = key.rsplit_once('.').expect("key should have ext");
+        format!(
+            "{}_{}.{}",
+            path,
+            size,
+            self.thumbnail_format().extensions_str()[0]
+        )
+    }
+
+    pub fn thumbnail_mime(&self) -> Mime {
+        self.thumbnail_format()
+            .to_mime_type()
+            .parse()
+            .expect("image format should have valid mime")
+    }
+
+    // images we are able to decode
+    pub fn is_image(&self) -> bool {
+        matches!(self.ext.as_str(), "png" | "jpg" | "jpeg" | "gif" | "webp")
+    }
+
+    // keep jpeg as jpeg, everything else is encoded as png
+    fn thumbnail_format(&self) -> ImageFormat {
+        match self.ext.as_str() {
+            "jpg" | "jpeg" => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        }
+    }
+}
+
+impl AppState {
+    /// Generate thumbnails for an uploaded image, one for each configured size.
+    /// Sizes not smaller than the image are skipped, returns the generated sizes.
+    pub async fn generate_thumbnails(
+        &self,
+        file: &ChatFile,
+        data: Bytes,
+    ) -> Result<Vec<u32>, AppError> {
+        if !file.is_image() {
+            return Ok(vec![]);
+        }
+
+        let sizes = self.config.upload.thumbnail_sizes.clone();
+        let format = file.thumbnail_format();
+        let thumbnails = task::spawn_blocking(move || create_thumbnails(&data, &sizes, format))
+            .await
+            .map_err(|e| AppError::ThumbnailError(e.to_string()))??;
+
+        let mut sizes = Vec::with_capacity(thumbnails.len());
+        for (size, data) in thumbnails {
+            self.store
+                .put(&file.thumbnail_key(size), data.into())
+                .await?;
+            sizes.push(size);
+        }
+
+        Ok(sizes)
+    }
+
+    /// Find key of the smallest thumbnail which is not smaller than the given size
+    pub async fn find_thumbnail(
+        &self,
+        file: &ChatFile,
+        size: u32,
+    ) -> Result<Option<(u32, String)>, AppError> {
+        if !file.is_image() {
+            return Ok(None);
+        }
+
+        let mut sizes: Vec<u32> = self
+            .config
+            .upload
+            .thumbnail_sizes
+            .iter()
+            .copied()
+            .filter(|s| *s >= size)
+            .collect();
+        sizes.sort();
+
+        for size in sizes {
+            let key = file.thumbnail_key(size);
+            if self.store.exists(&key).await? {
+                return Ok(Some((size, key)));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn create_thumbnails(
+    data: &[u8],
+    sizes: &[u32],
+    format: ImageFormat,
+) -> Result<Vec<(u32, Vec<u8>)>, AppError> {
+    let img = image::load_from_memory(data)?;
+
+    let mut thumbnails = Vec::new();
+    for &size in sizes {
+        if img.width() <= size && img.height() <= size {
+            continue;
+        }
+
+        let thumbnail = img.thumbnail(size, size);
+        // jpeg doesn't support alpha channel
+        let thumbnail = match format {
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(thumbnail.to_rgb8()),
+            _ => thumbnail,
+        };
+
+        let mut buf = Cursor::new(Vec::new());
+        thumbnail.write_to(&mut buf, format)?;
+        thumbnails.push((size, buf.into_inner()));
+    }
+
+    Ok(thumbnails)
+}
+
+// trust magic bytes of the content first, fallback to extension of the filename
+// only for plain text, so content could not claim a type it's not
+fn sniff_ext(filename: &str, data: &[u8]) -> String {
+    if let Some(kind) = infer::get(data) {
+        return kind.extension().to_string();
+    }
+
+    let ext = filename
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match ext {
+        Some(ext) if TEXT_EXTS.contains(&ext.as_str()) && std::str::from_utf8(data).is_ok() => ext,
+        _ => DEFAULT_EXT.to_string(),
+    }
+}
+
+// parse chat file back from its url: /files/{ws_id}/{part1}/{part2}/{part3}.{ext}
+impl FromStr for ChatFile {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || AppError::ChatFileError(format!("invalid chat file url: {}", s));
+
+        let path = s.strip_prefix("/files/").ok_or_else(invalid)?;
+        let parts: Vec<&str> = path.split('/').collect();
+        let [ws_id, part1, part2, part3] = parts[..] else {
+            return Err(invalid());
+        };
+        let ws_id = ws_id.parse::<i64>().map_err(|_| invalid())?;
+        let (part3, ext) = part3.split_once('.').ok_or_else(invalid)?;
+
+        let hash = format!("{}{}{}", part1, part2, part3);
+        if part1.len() != 3 || part2.len() != 3 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            ws_id,
+            ext: ext.to_string(),
+            hash,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -40,4 +215,102 @@ mod tests {
         assert_eq!(file.ext, "txt");
         assert_eq!(file.hash, "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
     }
+
+    #[test]
+    fn chat_file_should_sniff_ext() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        let file = ChatFile::new(0, "fake.txt", png);
+        assert_eq!(file.ext, "png");
+        assert_eq!(file.mime(), "image/png");
+
+        let file = ChatFile::new(0, "Notes.MD", b"# hello");
+        assert_eq!(file.ext, "md");
+
+        let file = ChatFile::new(0, "README", b"hello");
+        assert_eq!(file.ext, "bin");
+        assert_eq!(file.mime(), "application/octet-stream");
+
+        let file = ChatFile::new(0, "bad.t/xt", b"hello");
+        assert_eq!(file.ext, "bin");
+
+        // type comes from the content, so the allow/deny list applies to it
+        let file = ChatFile::new(0, "x.png", b"<html><script>alert(1)</script></html>");
+        assert_eq!(file.ext, "html");
+        // extension is not trusted for content that could not be sniffed
+        let file = ChatFile::new(0, "x.png", b"alert(1)");
+        assert_eq!(file.ext, "bin");
+        let file = ChatFile::new(0, "x.svg", b"<svg onload=\"alert(1)\"></svg>");
+        assert_eq!(file.ext, "bin");
+        let file = ChatFile::new(0, "x.txt", b"\xff\xfe\x00binary");
+        assert_eq!(file.ext, "bin");
+    }
+
+    #[test]
+    fn chat_file_thumbnail_key_should_work() {
+        let file = ChatFile::new(1, "test.txt", b"hello");
+        assert_eq!(
+            file.thumbnail_key(256),
+            "1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d_256.png"
+        );
+
+        let file = ChatFile {
+            ext: "jpeg".to_string(),
+            ..file
+        };
+        assert_eq!(
+            file.thumbnail_key(64),
+            "1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d_64.jpg"
+        );
+        assert_eq!(file.thumbnail_mime(), "image/jpeg");
+    }
+
+    #[tokio::test]
+    async fn generate_thumbnails_should_work() -> anyhow::Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let img = DynamicImage::new_rgba8(600, 300);
+        let mut data = Cursor::new(Vec::new());
+        img.write_to(&mut data, ImageFormat::Png)?;
+        let data = data.into_inner();
+
+        let file = ChatFile::new(1, "image.png", &data);
+        let sizes = state.generate_thumbnails(&file, data.into()).await?;
+        // 1024 is larger than the image itself
+        assert_eq!(sizes, vec![64, 256]);
+
+        let thumbnail = state.store.get(&file.thumbnail_key(256)).await?;
+        let thumbnail = image::load_from_memory(&thumbnail)?;
+        assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+
+        let (size, key) = state.find_thumbnail(&file, 100).await?.unwrap();
+        assert_eq!(size, 256);
+        assert_eq!(key, file.thumbnail_key(256));
+        assert!(state.find_thumbnail(&file, 512).await?.is_none());
+
+        // not an image
+        let file = ChatFile::new(1, "test.txt", b"hello");
+        let sizes = state.generate_thumbnails(&file, "hello".into()).await?;
+        assert!(sizes.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn chat_file_should_parse_from_url() {
+        let file = ChatFile::new(1, "test.txt", b"hello");
+        let url = file.url();
+        assert_eq!(
+            url,
+            "/files/1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt"
+        );
+
+        let parsed: ChatFile = url.parse().unwrap();
+        assert_eq!(parsed, file);
+
+        assert!("/files/1/aaf/4c6.txt".parse::<ChatFile>().is_err());
+        assert!("/files/x/aaf/4c6/1dd.txt".parse::<ChatFile>().is_err());
+        assert!("/other/1/aaf/4c6/1dd.txt".parse::<ChatFile>().is_err());
+        assert!("/files/1/aaf/4c6/1dd".parse::<ChatFile>().is_err());
+        assert!("/files/1/../4c6/1dd.txt".parse::<ChatFile>().is_err());
+    }
 }
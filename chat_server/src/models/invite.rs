@@ -0,0 +1,172 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
+
+use super::token::{generate_token, hash_token};
+use crate::{AppError, AppState};
+
+const DEFAULT_INVITE_DURATION: i64 = 60 * 60 * 24 * 7; // 7 days
+const MAX_INVITE_DURATION: i64 = 60 * 60 * 24 * 30; // 30 days
+const MAX_INVITE_USES: i32 = 100;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CreateInvite {
+    #[serde(default = "default_max_uses")]
+    pub max_uses: i32,
+    // seconds until the invite expires
+    #[serde(default = "default_invite_duration")]
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceInvite {
+    pub id: i64,
+    pub ws_id: i64,
+    // only returned on creation, the db keeps its hash
+    #[sqlx(skip)]
+    pub token: String,
+    pub max_uses: i32,
+    pub used_count: i32,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Create an invite to workspace `ws_id`, the caller is responsible for
+    /// making sure `user_id` is a member of the workspace.
+    pub async fn create_invite(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+        input: &CreateInvite,
+    ) -> Result<WorkspaceInvite, AppError> {
+        if !(1..=MAX_INVITE_USES).contains(&input.max_uses) {
+            return Err(AppError::InviteError(format!(
+                "max_uses must be between 1 and {}",
+                MAX_INVITE_USES
+            )));
+        }
+
+        if !(1..=MAX_INVITE_DURATION).contains(&input.expires_in) {
+            return Err(AppError::InviteError(format!(
+                "expires_in must be between 1 and {} seconds",
+                MAX_INVITE_DURATION
+            )));
+        }
+
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::seconds(input.expires_in);
+        let invite: WorkspaceInvite = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_invites (ws_id, created_by, token_hash, max_uses, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, max_uses, used_count, expires_at
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(input.max_uses)
+        .bind(expires_at)
+        .fetch_one(&self.pg_pool)
+        .await?;
+
+        Ok(WorkspaceInvite { token, ..invite })
+    }
+}
+
+/// Use the invite once and return its workspace id. Runs in the signup
+/// transaction so that the invite is not used up if signup fails.
+pub(super) async fn consume_invite(conn: &mut PgConnection, token: &str) -> Result<i64, AppError> {
+    let ws_id: Option<i64> = sqlx::query_scalar(
+        r#"
+        UPDATE workspace_invites
+        SET used_count = used_count + 1
+        WHERE token_hash = $1 AND used_count < max_uses AND expires_at > NOW()
+        RETURNING ws_id
+        "#,
+    )
+    .bind(hash_token(token))
+    .fetch_optional(conn)
+    .await?;
+
+    ws_id.ok_or_else(|| AppError::InviteError("invite is invalid or expired".to_string()))
+}
+
+fn default_max_uses() -> i32 {
+    1
+}
+
+fn default_invite_duration() -> i64 {
+    DEFAULT_INVITE_DURATION
+}
+
+#[cfg(test)]
+impl CreateInvite {
+    pub fn new(max_uses: i32, expires_in: i64) -> Self {
+        Self {
+            max_uses,
+            expires_in,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn invite_should_be_used_up() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let invite = state.create_invite(1, 1, &CreateInvite::new(2, 60)).await?;
+        assert_eq!(invite.ws_id, 1);
+        assert_eq!(invite.token.len(), 64);
+
+        for i in 0..2 {
+            let email = format!("user{}@acme.org", i);
+            let input = CreateUser::with_invite(&invite.token, "user", &email, "123456");
+            let user = state.create_user(&input).await?;
+            assert_eq!(user.ws_id, 1);
+        }
+
+        let input = CreateUser::with_invite(&invite.token, "user", "user2@acme.org", "123456");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_invite_should_be_rejected() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let invite = state.create_invite(1, 1, &CreateInvite::new(1, 60)).await?;
+        sqlx::query("UPDATE workspace_invites SET expires_at = NOW() - INTERVAL '1 second'")
+            .execute(&state.pg_pool)
+            .await?;
+
+        let input = CreateUser::with_invite(&invite.token, "user", "user@acme.org", "123456");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+
+        let input = CreateUser::with_invite("unknown", "user", "user@acme.org", "123456");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_invite_should_check_limits() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state.create_invite(1, 1, &CreateInvite::new(0, 60)).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+
+        let ret = state
+            .create_invite(1, 1, &CreateInvite::new(1, MAX_INVITE_DURATION + 1))
+            .await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+
+        let input: CreateInvite = serde_json::from_str("{}")?;
+        assert_eq!(input, CreateInvite::new(1, DEFAULT_INVITE_DURATION));
+        Ok(())
+    }
+}
@@ -1,15 +1,24 @@
 mod chat;
 mod file;
+mod invite;
 mod msgs;
+mod reaction;
+mod read;
+mod token;
 mod user;
 mod workspace;
 
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 
-pub use chat::{CreateChat, UpdateChat};
-pub use msgs::CreateMessage;
+pub use chat::{CreateChat, ListChats, UpdateChat};
+#[allow(unused)]
+pub use invite::{CreateInvite, WorkspaceInvite};
+pub use msgs::{CreateMessage, ListMessages, UpdateMessage};
+pub use read::MarkRead;
+pub use token::RefreshToken;
 pub use user::{CreateUser, SignInUser};
+pub use workspace::{JoinWorkspace, ListUsers, TransferOwner, UpdateMember};
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
 pub struct ChatFile {
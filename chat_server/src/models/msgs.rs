@@ -1,47 +1,372 @@
-use chat_core::Message;
+use chat_core::{Message, User};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 
-use crate::{AppError, AppState};
+use crate::{models::ChatFile, AppError, AppState};
+
+const DEFAULT_MESSAGE_LIMIT: u64 = 20;
+const MAX_MESSAGE_LIMIT: u64 = 100;
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
 pub struct CreateMessage {
-    pub chat_id: i64,
-    pub sender_id: i64,
     pub content: String,
+    // urls of files uploaded by `upload_file_handler`
+    #[serde(default)]
+    pub files: Vec<String>,
+    // reply in the thread of this message
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+}
+
+// only content could be edited, files are kept
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UpdateMessage {
+    pub content: String,
+}
+
+// previous version of an edited message
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MessageEdit {
+    pub id: i64,
+    pub message_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ListMessages {
+    // fetch messages older than this one, None for the latest page
+    pub last_id: Option<i64>,
+    #[serde(default = "default_message_limit")]
+    pub limit: u64,
 }
 
 impl AppState {
-    pub async fn create_message(&self, input: &CreateMessage) -> Result<Message, AppError> {
+    /// Create a message in chat `chat_id` sent by `user`,
+    /// the caller is responsible for making sure user is a member of the chat.
+    pub async fn create_message(
+        &self,
+        input: &CreateMessage,
+        chat_id: i64,
+        user: &User,
+    ) -> Result<Message, AppError> {
+        let content = input.content.trim();
+        self.verify_content(content, &input.files)
+            .map_err(AppError::CreateMessageError)?;
+
+        for url in &input.files {
+            self.verify_message_file(url, user.ws_id).await?;
+        }
+
+        let thread_root_id = match input.reply_to {
+            Some(id) => Some(self.get_thread_root(chat_id, id).await?),
+            None => None,
+        };
+
+        // archived chats are read-only
+        let msg: Option<Message> = sqlx::query_as(
+            r#"
+            INSERT INTO messages (chat_id, sender_id, content, files, thread_root_id)
+            SELECT $1, $2, $3, $4, $5
+            WHERE EXISTS (
+                SELECT 1 FROM chats
+                WHERE id = $1 AND archived_at IS NULL AND deleted_at IS NULL
+            )
+            RETURNING id, chat_id, sender_id, thread_root_id, content, files, edited_at, deleted_at, created_at
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user.id)
+        .bind(content)
+        .bind(&input.files)
+        .bind(thread_root_id)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        msg.ok_or_else(|| {
+            AppError::CreateMessageError(format!("chat {} is archived or deleted", chat_id))
+        })
+    }
+
+    /// Fetch a page of messages in chat, newest first. Use the id of the last
+    /// message in the page as `last_id` to fetch the next (older) page.
+    /// Replies are not included, root messages come with their reply stats.
+    /// Reactions are counted for `user_id`.
+    pub async fn list_messages(
+        &self,
+        input: &ListMessages,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        self.fetch_message_page(input, chat_id, None, user_id).await
+    }
+
+    /// Fetch a page of replies in the thread of message `root_id`, paged as `list_messages`
+    pub async fn list_replies(
+        &self,
+        input: &ListMessages,
+        chat_id: i64,
+        root_id: i64,
+        user_id: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        match self.find_message(chat_id, root_id).await? {
+            Some(msg) if msg.thread_root_id.is_none() => {}
+            _ => return Err(AppError::NotFound(format!("thread {}", root_id))),
+        }
+        self.fetch_message_page(input, chat_id, Some(root_id), user_id)
+            .await
+    }
+
+    async fn fetch_message_page(
+        &self,
+        input: &ListMessages,
+        chat_id: i64,
+        thread_root_id: Option<i64>,
+        user_id: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        let limit = input.limit.clamp(1, MAX_MESSAGE_LIMIT);
+
+        // (created_at, id) keeps order stable when messages share the same timestamp
+        let mut msgs = sqlx::query_as(
+            r#"
+            SELECT m.id, m.chat_id, m.sender_id, m.thread_root_id, m.content, m.files,
+                m.edited_at, m.deleted_at, m.created_at, t.reply_count, t.last_reply_at
+            FROM messages m
+            LEFT JOIN LATERAL (
+                SELECT count(*) AS reply_count, max(r.created_at) AS last_reply_at
+                FROM messages r
+                WHERE r.thread_root_id = m.id AND r.deleted_at IS NULL
+            ) t ON TRUE
+            WHERE m.chat_id = $1 AND m.thread_root_id IS NOT DISTINCT FROM $4
+            AND ($2::BIGINT IS NULL
+                OR (m.created_at, m.id) < (SELECT created_at, id FROM messages WHERE id = $2))
+            ORDER BY m.created_at DESC, m.id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(chat_id)
+        .bind(input.last_id)
+        .bind(limit as i64)
+        .bind(thread_root_id)
+        .fetch_all(&self.pg_pool)
+        .await?;
+        self.fill_reactions(&mut msgs, user_id).await?;
+
+        Ok(msgs)
+    }
+
+    // replying to a reply goes to the same thread
+    async fn get_thread_root(&self, chat_id: i64, id: i64) -> Result<i64, AppError> {
+        match self.find_message(chat_id, id).await? {
+            Some(msg) if msg.deleted_at.is_none() => Ok(msg.thread_root_id.unwrap_or(msg.id)),
+            _ => Err(AppError::CreateMessageError(format!(
+                "message {} not found in chat {}",
+                id, chat_id
+            ))),
+        }
+    }
+
+    pub async fn find_message(&self, chat_id: i64, id: i64) -> Result<Option<Message>, AppError> {
         let msg = sqlx::query_as(
             r#"
-            INSERT INTO messages (chat_id, sender_id, content)
+            SELECT id, chat_id, sender_id, thread_root_id, content, files, edited_at, deleted_at, created_at
+            FROM messages
+            WHERE id = $1 AND chat_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(chat_id)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        Ok(msg)
+    }
+
+    /// Replace content of a message, the previous version is kept in its history.
+    /// The caller is responsible for checking user could edit the message.
+    pub async fn update_message(
+        &self,
+        chat_id: i64,
+        id: i64,
+        input: &UpdateMessage,
+    ) -> Result<Message, AppError> {
+        let mut tx = self.pg_pool.begin().await?;
+        let msg = lock_message(&mut tx, chat_id, id).await?;
+
+        let content = input.content.trim();
+        self.verify_content(content, &msg.files)
+            .map_err(AppError::UpdateMessageError)?;
+        if content == msg.content {
+            return Ok(msg);
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO message_edits (message_id, content, files)
             VALUES ($1, $2, $3)
-            RETURNING id, chat_id, sender_id, content, created_at
             "#,
         )
-        .bind(input.chat_id)
-        .bind(input.sender_id)
-        .bind(&input.content)
-        .fetch_one(&self.pg_pool)
+        .bind(id)
+        .bind(&msg.content)
+        .bind(&msg.files)
+        .execute(&mut *tx)
         .await?;
 
+        let msg = sqlx::query_as(
+            r#"
+            UPDATE messages
+            SET content = $1, edited_at = NOW()
+            WHERE id = $2
+            RETURNING id, chat_id, sender_id, thread_root_id, content, files, edited_at, deleted_at, created_at
+            "#,
+        )
+        .bind(content)
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
         Ok(msg)
     }
 
-    pub async fn fetch_messages(&self, chat_id: i64) -> Result<Vec<Message>, AppError> {
-        let msgs = sqlx::query_as(
+    /// Clear content and files of a message along with its history and reactions, the message
+    /// itself is kept as a tombstone.
+    pub async fn delete_message(&self, chat_id: i64, id: i64) -> Result<(), AppError> {
+        let mut tx = self.pg_pool.begin().await?;
+        lock_message(&mut tx, chat_id, id).await?;
+
+        sqlx::query(
             r#"
-            SELECT * FROM messages
-            WHERE chat_id = $1
-            ORDER BY id DESC
+            UPDATE messages
+            SET content = '', files = '{}', deleted_at = NOW()
+            WHERE id = $1
             "#,
         )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM message_edits WHERE message_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM message_reactions WHERE message_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Previous versions of a message, oldest first
+    pub async fn fetch_message_edits(
+        &self,
+        chat_id: i64,
+        id: i64,
+    ) -> Result<Vec<MessageEdit>, AppError> {
+        let edits = sqlx::query_as(
+            r#"
+            SELECT e.id, e.message_id, e.content, e.files, e.created_at
+            FROM message_edits e
+            JOIN messages m ON m.id = e.message_id
+            WHERE m.id = $1 AND m.chat_id = $2
+            ORDER BY e.id
+            "#,
+        )
+        .bind(id)
         .bind(chat_id)
         .fetch_all(&self.pg_pool)
         .await?;
 
-        Ok(msgs)
+        Ok(edits)
+    }
+
+    fn verify_content(&self, content: &str, files: &[String]) -> Result<(), String> {
+        if content.is_empty() && files.is_empty() {
+            return Err("content and files cannot both be empty".to_string());
+        }
+
+        let max_len = self.config.server.max_message_len;
+        if content.chars().count() > max_len {
+            return Err(format!("content cannot exceed {} characters", max_len));
+        }
+        Ok(())
+    }
+
+    // attached file must be uploaded to the sender's workspace
+    async fn verify_message_file(&self, url: &str, ws_id: i64) -> Result<(), AppError> {
+        let file: ChatFile = url
+            .parse()
+            .map_err(|e: AppError| AppError::CreateMessageError(e.to_string()))?;
+
+        if file.ws_id != ws_id {
+            return Err(AppError::CreateMessageError(format!(
+                "file {} does not belong to workspace {}",
+                url, ws_id
+            )));
+        }
+
+        if !self.store.exists(&file.key()).await? {
+            return Err(AppError::CreateMessageError(format!(
+                "file {} does not exist",
+                url
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+// lock a message being changed, deleted messages and archived chats are read-only
+pub(super) async fn lock_message(
+    conn: &mut PgConnection,
+    chat_id: i64,
+    id: i64,
+) -> Result<Message, AppError> {
+    let msg: Message = sqlx::query_as(
+        r#"
+        SELECT id, chat_id, sender_id, thread_root_id, content, files, edited_at, deleted_at, created_at
+        FROM messages
+        WHERE id = $1 AND chat_id = $2 AND deleted_at IS NULL
+        FOR UPDATE
+        "#,
+    )
+    .bind(id)
+    .bind(chat_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| AppError::NotFound(format!("message {}", id)))?;
+
+    let archived: bool =
+        sqlx::query_scalar("SELECT archived_at IS NOT NULL FROM chats WHERE id = $1")
+            .bind(chat_id)
+            .fetch_one(&mut *conn)
+            .await?;
+    if archived {
+        return Err(AppError::UpdateMessageError(format!(
+            "chat {} is archived",
+            chat_id
+        )));
+    }
+
+    Ok(msg)
+}
+
+fn default_message_limit() -> u64 {
+    DEFAULT_MESSAGE_LIMIT
+}
+
+#[cfg(test)]
+impl CreateMessage {
+    pub fn new(content: &str, files: &[String]) -> Self {
+        Self {
+            content: content.to_string(),
+            files: files.to_vec(),
+            reply_to: None,
+        }
     }
 }
 
@@ -49,28 +374,240 @@ impl AppState {
 mod tests {
     use super::*;
 
+    async fn get_user(state: &AppState, email: &str) -> User {
+        state
+            .find_user_by_email(email)
+            .await
+            .unwrap()
+            .expect("user should exist")
+    }
+
     #[tokio::test]
     async fn test_create_message() {
         let (_tdb, state) = AppState::new_for_test().await.unwrap();
+        let user = get_user(&state, "alice@acme.org").await;
+
+        let msg = CreateMessage::new("test message", &[]);
+        let ret = state.create_message(&msg, 1, &user).await.unwrap();
+
+        assert_eq!(ret.chat_id, 1);
+        assert_eq!(ret.sender_id, user.id);
+        assert_eq!(ret.content, "test message");
+        assert!(ret.files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_create_message_should_reject_invalid_content() {
+        let (_tdb, state) = AppState::new_for_test().await.unwrap();
+        let user = get_user(&state, "tchen@acme.org").await;
+
+        let msg = CreateMessage::new("  ", &[]);
+        let ret = state.create_message(&msg, 1, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        let max_len = state.config.server.max_message_len;
+        let msg = CreateMessage::new(&"a".repeat(max_len + 1), &[]);
+        let ret = state.create_message(&msg, 1, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_create_message_with_files() {
+        let (_tdb, state) = AppState::new_for_test().await.unwrap();
+        let user = get_user(&state, "tchen@acme.org").await;
+
+        let file = ChatFile::new(user.ws_id, "hello.txt", b"hello attachment");
+        state
+            .store
+            .put(&file.key(), "hello attachment".into())
+            .await
+            .unwrap();
+
+        let msg = CreateMessage::new("", &[file.url()]);
+        let ret = state.create_message(&msg, 1, &user).await.unwrap();
+        assert_eq!(ret.files, vec![file.url()]);
+
+        // file not uploaded
+        let missing = ChatFile::new(user.ws_id, "missing.txt", b"not uploaded");
+        let msg = CreateMessage::new("test", &[missing.url()]);
+        let ret = state.create_message(&msg, 1, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        // file from another workspace
+        let other = ChatFile::new(user.ws_id + 1, "hello.txt", b"hello attachment");
+        let msg = CreateMessage::new("test", &[other.url()]);
+        let ret = state.create_message(&msg, 1, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+
+        // invalid url
+        let msg = CreateMessage::new("test", &["/files/../../etc/passwd".to_string()]);
+        let ret = state.create_message(&msg, 1, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_create_message_should_allow_max_len_multibyte() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = get_user(&state, "tchen@acme.org").await;
 
-        let msg = CreateMessage {
-            chat_id: 1,
-            sender_id: 1,
-            content: "test message".to_string(),
+        // over the 8000 bytes notify payload limit, only the id is notified
+        let content = "中".repeat(state.config.server.max_message_len);
+        let msg = state
+            .create_message(&CreateMessage::new(&content, &[]), 1, &user)
+            .await?;
+        let input = UpdateMessage {
+            content: "文".repeat(state.config.server.max_message_len),
         };
+        state.update_message(1, msg.id, &input).await?;
+        Ok(())
+    }
 
-        let ret = state.create_message(&msg).await.unwrap();
+    #[tokio::test]
+    async fn test_list_messages() {
+        let (_tdb, state) = AppState::new_for_test().await.unwrap();
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 6,
+        };
+        let msgs = state.list_messages(&input, 1, 1).await.unwrap();
+        assert_eq!(msgs.len(), 6);
+        assert_eq!(msgs[0].id, 10);
+        assert_eq!(msgs[5].id, 5);
 
-        assert_eq!(msg.chat_id, ret.chat_id);
-        assert_eq!(msg.sender_id, 1);
-        assert_eq!(msg.content, "test message");
+        let input = ListMessages {
+            last_id: Some(msgs[5].id),
+            limit: 6,
+        };
+        let msgs = state.list_messages(&input, 1, 1).await.unwrap();
+        assert_eq!(msgs.len(), 4);
+        assert_eq!(msgs[0].id, 4);
+        assert_eq!(msgs[3].id, 1);
+
+        let input = ListMessages {
+            last_id: Some(msgs[3].id),
+            limit: 6,
+        };
+        let msgs = state.list_messages(&input, 1, 1).await.unwrap();
+        assert!(msgs.is_empty());
     }
 
     #[tokio::test]
-    async fn test_fetch_messages() {
+    async fn test_list_messages_should_cap_limit() {
         let (_tdb, state) = AppState::new_for_test().await.unwrap();
-        let msgs = state.fetch_messages(1).await.unwrap();
+        let user = get_user(&state, "tchen@acme.org").await;
+        for i in 0..MAX_MESSAGE_LIMIT {
+            let msg = CreateMessage::new(&format!("message {}", i), &[]);
+            state.create_message(&msg, 1, &user).await.unwrap();
+        }
+
+        let input = ListMessages {
+            last_id: None,
+            limit: MAX_MESSAGE_LIMIT * 2,
+        };
+        let msgs = state.list_messages(&input, 1, 1).await.unwrap();
+        assert_eq!(msgs.len() as u64, MAX_MESSAGE_LIMIT);
+
+        let input: ListMessages = serde_json::from_str("{}").unwrap();
+        assert_eq!(input.limit, DEFAULT_MESSAGE_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn test_update_message_should_keep_history() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = get_user(&state, "alice@acme.org").await;
+        let msg = state
+            .create_message(&CreateMessage::new("first", &[]), 1, &user)
+            .await?;
+
+        for content in ["second", "third"] {
+            let input = UpdateMessage {
+                content: content.to_string(),
+            };
+            state.update_message(1, msg.id, &input).await?;
+        }
+        let updated = state.find_message(1, msg.id).await?.unwrap();
+        assert_eq!(updated.content, "third");
+        assert!(updated.edited_at.is_some());
+
+        let edits = state.fetch_message_edits(1, msg.id).await?;
+        let contents: Vec<_> = edits.iter().map(|e| e.content.as_str()).collect();
+        assert_eq!(contents, vec!["first", "second"]);
+
+        let input = UpdateMessage {
+            content: " ".to_string(),
+        };
+        let ret = state.update_message(1, msg.id, &input).await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
 
+        // message of another chat
+        let ret = state.update_message(2, msg.id, &input).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_message_should_leave_tombstone() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = UpdateMessage {
+            content: "edited".to_string(),
+        };
+        state.update_message(1, 1, &input).await?;
+        state.delete_message(1, 1).await?;
+
+        let msg = state.find_message(1, 1).await?.unwrap();
+        assert!(msg.deleted_at.is_some());
+        assert!(msg.content.is_empty());
+        assert!(state.fetch_message_edits(1, 1).await?.is_empty());
+
+        let ret = state.update_message(1, 1, &input).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let ret = state.delete_message(1, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // archived chats are read-only
+        state.archive_chat(1, true).await?;
+        let ret = state.delete_message(1, 2).await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replies_should_be_threaded() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = get_user(&state, "bob@acme.org").await;
+
+        let mut input = CreateMessage::new("reply", &[]);
+        input.reply_to = Some(1);
+        let reply = state.create_message(&input, 1, &user).await?;
+        assert_eq!(reply.thread_root_id, Some(1));
+
+        // reply to a reply stays in the same thread
+        input.reply_to = Some(reply.id);
+        let nested = state.create_message(&input, 1, &user).await?;
+        assert_eq!(nested.thread_root_id, Some(1));
+
+        let page = ListMessages {
+            last_id: None,
+            limit: 20,
+        };
+        let msgs = state.list_messages(&page, 1, 1).await?;
         assert_eq!(msgs.len(), 10);
+        let root = msgs.iter().find(|m| m.id == 1).unwrap();
+        assert_eq!(root.reply_count, 2);
+        assert_eq!(root.last_reply_at, Some(nested.created_at));
+
+        let replies = state.list_replies(&page, 1, 1, 1).await?;
+        let ids: Vec<_> = replies.iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![nested.id, reply.id]);
+
+        let ret = state.list_replies(&page, 1, reply.id, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // replies must be in the same chat
+        input.reply_to = Some(1);
+        let ret = state.create_message(&input, 2, &user).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
     }
 }
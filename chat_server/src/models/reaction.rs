@@ -0,0 +1,171 @@
+use chat_core::{Message, ReactionCount};
+use sqlx::FromRow;
+
+use super::msgs::lock_message;
+use crate::{AppError, AppState};
+
+#[derive(Debug, FromRow)]
+struct MessageReactionCount {
+    message_id: i64,
+    #[sqlx(flatten)]
+    reaction: ReactionCount,
+}
+
+impl AppState {
+    /// React to a message with emoji, reacting twice is a no-op.
+    /// The caller is responsible for making sure user is a member of the chat.
+    pub async fn add_reaction(
+        &self,
+        chat_id: i64,
+        msg_id: i64,
+        user_id: i64,
+        emoji: &str,
+    ) -> Result<(), AppError> {
+        let emoji = parse_emoji(emoji)?;
+
+        let mut tx = self.pg_pool.begin().await?;
+        lock_message(&mut tx, chat_id, msg_id).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO message_reactions (message_id, user_id, emoji)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(msg_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Remove reaction of user from a message, removing a missing reaction is a no-op
+    pub async fn remove_reaction(
+        &self,
+        chat_id: i64,
+        msg_id: i64,
+        user_id: i64,
+        emoji: &str,
+    ) -> Result<(), AppError> {
+        let emoji = parse_emoji(emoji)?;
+
+        let mut tx = self.pg_pool.begin().await?;
+        lock_message(&mut tx, chat_id, msg_id).await?;
+        sqlx::query(
+            r#"
+            DELETE FROM message_reactions
+            WHERE message_id = $1 AND user_id = $2 AND emoji = $3
+            "#,
+        )
+        .bind(msg_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Attach reaction counts to messages, in the order the emojis were first used
+    pub(super) async fn fill_reactions(
+        &self,
+        msgs: &mut [Message],
+        user_id: i64,
+    ) -> Result<(), AppError> {
+        let ids: Vec<i64> = msgs.iter().map(|m| m.id).collect();
+        let counts: Vec<MessageReactionCount> = sqlx::query_as(
+            r#"
+            SELECT message_id, emoji, count(*) AS count, bool_or(user_id = $2) AS reacted
+            FROM message_reactions
+            WHERE message_id = ANY($1)
+            GROUP BY message_id, emoji
+            ORDER BY min(created_at), emoji
+            "#,
+        )
+        .bind(&ids)
+        .bind(user_id)
+        .fetch_all(&self.pg_pool)
+        .await?;
+
+        for count in counts {
+            if let Some(msg) = msgs.iter_mut().find(|m| m.id == count.message_id) {
+                msg.reactions.push(count.reaction);
+            }
+        }
+        Ok(())
+    }
+}
+
+// either an emoji or its :shortcode:, stored as the fully qualified emoji
+fn parse_emoji(emoji: &str) -> Result<&'static str, AppError> {
+    let found = match emoji.strip_prefix(':').and_then(|s| s.strip_suffix(':')) {
+        Some(shortcode) => emojis::get_by_shortcode(shortcode),
+        None => emojis::get(emoji),
+    };
+    found
+        .map(|e| e.as_str())
+        .ok_or_else(|| AppError::InvalidReaction(format!("{:?} is not an emoji", emoji)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ListMessages;
+
+    #[tokio::test]
+    async fn reactions_should_be_counted() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.add_reaction(1, 1, 1, "👍").await?;
+        state.add_reaction(1, 1, 2, "👍").await?;
+        // reacting twice is a no-op
+        state.add_reaction(1, 1, 2, "👍").await?;
+        state.add_reaction(1, 1, 2, "🎉").await?;
+
+        let input = ListMessages {
+            last_id: None,
+            limit: 20,
+        };
+        let msgs = state.list_messages(&input, 1, 1).await?;
+        let msg = msgs.iter().find(|m| m.id == 1).unwrap();
+        let counts: Vec<_> = msg
+            .reactions
+            .iter()
+            .map(|r| (r.emoji.as_str(), r.count, r.reacted))
+            .collect();
+        assert_eq!(counts, vec![("👍", 2, true), ("🎉", 1, false)]);
+
+        state.remove_reaction(1, 1, 2, "🎉").await?;
+        state.remove_reaction(1, 1, 2, "🎉").await?;
+        let msgs = state.list_messages(&input, 1, 2).await?;
+        let msg = msgs.iter().find(|m| m.id == 1).unwrap();
+        assert_eq!(msg.reactions.len(), 1);
+        assert!(msg.reactions[0].reacted);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reaction_should_be_validated() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for emoji in ["", "a b", "lol", ":lol:", "👍👍"] {
+            let ret = state.add_reaction(1, 1, 1, emoji).await;
+            assert!(matches!(ret, Err(AppError::InvalidReaction(_))));
+        }
+
+        // shortcode is the same reaction as the emoji
+        state.add_reaction(1, 1, 1, ":rocket:").await?;
+        state.remove_reaction(1, 1, 1, "🚀").await?;
+        let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM message_reactions")
+            .fetch_one(&state.pg_pool)
+            .await?;
+        assert_eq!(count, 0);
+
+        // message of another chat
+        let ret = state.add_reaction(2, 1, 1, "👍").await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+}
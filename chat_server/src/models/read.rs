@@ -0,0 +1,159 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::{AppError, AppState};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkRead {
+    // read up to this message, None for the latest one
+    pub message_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatRead {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub last_read_message_id: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl AppState {
+    /// Mark chat as read by user up to a message. Read position never moves
+    /// back, so the current one is returned if it's already past the message.
+    /// There's nothing to read in an empty chat, None is returned.
+    pub async fn mark_chat_read(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        input: &MarkRead,
+    ) -> Result<Option<ChatRead>, AppError> {
+        let message_id: i64 = match input.message_id {
+            Some(id) => {
+                sqlx::query_scalar("SELECT id FROM messages WHERE id = $1 AND chat_id = $2")
+                    .bind(id)
+                    .bind(chat_id)
+                    .fetch_optional(&self.pg_pool)
+                    .await?
+                    .ok_or_else(|| {
+                        AppError::NotFound(format!("message {} in chat {}", id, chat_id))
+                    })?
+            }
+            None => {
+                let latest: Option<i64> =
+                    sqlx::query_scalar("SELECT max(id) FROM messages WHERE chat_id = $1")
+                        .bind(chat_id)
+                        .fetch_one(&self.pg_pool)
+                        .await?;
+                match latest {
+                    Some(id) => id,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let read: Option<ChatRead> = sqlx::query_as(
+            r#"
+            INSERT INTO chat_reads (chat_id, user_id, last_read_message_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (chat_id, user_id) DO UPDATE
+            SET last_read_message_id = EXCLUDED.last_read_message_id, updated_at = NOW()
+            WHERE chat_reads.last_read_message_id < EXCLUDED.last_read_message_id
+            RETURNING chat_id, user_id, last_read_message_id, updated_at
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(message_id)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        match read {
+            Some(read) => Ok(Some(read)),
+            None => Ok(sqlx::query_as(
+                r#"
+                SELECT chat_id, user_id, last_read_message_id, updated_at
+                FROM chat_reads
+                WHERE chat_id = $1 AND user_id = $2
+                "#,
+            )
+            .bind(chat_id)
+            .bind(user_id)
+            .fetch_optional(&self.pg_pool)
+            .await?),
+        }
+    }
+}
+
+#[cfg(test)]
+impl MarkRead {
+    pub fn new(message_id: i64) -> Self {
+        Self {
+            message_id: Some(message_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateMessage, ListChats};
+
+    async fn unread_count(state: &AppState, chat_id: i64, user_id: i64) -> Result<i64, AppError> {
+        let chats = state.fetch_chats(1, user_id, &ListChats::default()).await?;
+        Ok(chats
+            .into_iter()
+            .find(|c| c.id == chat_id)
+            .expect("chat should exist")
+            .unread_count)
+    }
+
+    #[tokio::test]
+    async fn mark_read_should_update_unread_count() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // 10 messages in chat 1, 2 of them sent by bob
+        assert_eq!(unread_count(&state, 1, 3).await?, 8);
+
+        let read = state
+            .mark_chat_read(1, 3, &MarkRead::new(5))
+            .await?
+            .unwrap();
+        assert_eq!(read.last_read_message_id, 5);
+        assert_eq!(unread_count(&state, 1, 3).await?, 4);
+
+        // read position never moves back
+        let read = state
+            .mark_chat_read(1, 3, &MarkRead::new(2))
+            .await?
+            .unwrap();
+        assert_eq!(read.last_read_message_id, 5);
+
+        let read = state
+            .mark_chat_read(1, 3, &MarkRead::default())
+            .await?
+            .unwrap();
+        assert_eq!(read.last_read_message_id, 10);
+        assert_eq!(unread_count(&state, 1, 3).await?, 0);
+
+        // replies are counted in their threads, not in the chat
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateMessage {
+            reply_to: Some(1),
+            ..CreateMessage::new("reply", &[])
+        };
+        state.create_message(&input, 1, &user).await?;
+        assert_eq!(unread_count(&state, 1, 3).await?, 0);
+        state
+            .create_message(&CreateMessage::new("hello", &[]), 1, &user)
+            .await?;
+        assert_eq!(unread_count(&state, 1, 3).await?, 1);
+
+        let ret = state.mark_chat_read(2, 3, &MarkRead::new(1)).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        // nothing to read in an empty chat
+        let read = state.mark_chat_read(2, 3, &MarkRead::default()).await?;
+        assert!(read.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::{AppError, AppState};
+
+const REFRESH_TOKEN_DURATION: i64 = 60 * 60 * 24 * 30; // 30 days
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshToken {
+    pub refresh_token: String,
+}
+
+impl AppState {
+    /// Create a new refresh token for user, only its hash is persisted
+    pub async fn create_refresh_token(&self, user_id: i64) -> Result<String, AppError> {
+        let token = generate_token();
+
+        let expires_at = Utc::now() + Duration::seconds(REFRESH_TOKEN_DURATION);
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (user_id, token_hash, expires_at)
+            VALUES ($1, $2, $3)
+            "#,
+        )
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(&self.pg_pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Revoke the refresh token and return its user id, the caller should issue
+    /// a new pair of tokens. Reusing a rotated token revokes all refresh tokens
+    /// of the user, since the token is likely leaked. Tokens revoked otherwise,
+    /// e.g. by signing out, are simply rejected.
+    pub async fn rotate_refresh_token(&self, token: &str) -> Result<i64, AppError> {
+        let token_hash = hash_token(token);
+        let user_id: Option<i64> = sqlx::query_scalar(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW(), rotated_at = NOW()
+            WHERE token_hash = $1 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING user_id
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        if let Some(user_id) = user_id {
+            return Ok(user_id);
+        }
+
+        let reused: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT user_id FROM refresh_tokens
+            WHERE token_hash = $1 AND rotated_at IS NOT NULL
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        if let Some(user_id) = reused {
+            self.revoke_user_refresh_tokens(user_id).await?;
+        }
+
+        Err(AppError::InvalidToken(
+            "refresh token is invalid or expired".to_string(),
+        ))
+    }
+
+    pub async fn revoke_refresh_token(&self, token: &str, user_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW()
+            WHERE token_hash = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(hash_token(token))
+        .bind(user_id)
+        .execute(&self.pg_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_user_refresh_tokens(&self, user_id: i64) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&self.pg_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Revoke an access token by its jwt id before it expires
+    pub async fn revoke_token(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(jti)
+        .bind(expires_at)
+        .execute(&self.pg_pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn is_token_revoked(&self, jti: &str) -> Result<bool, AppError> {
+        let revoked = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)
+            "#,
+        )
+        .bind(jti)
+        .fetch_one(&self.pg_pool)
+        .await?;
+
+        Ok(revoked)
+    }
+
+    /// Remove revoked tokens which have expired and would be rejected anyway,
+    /// returns the number removed
+    pub async fn purge_revoked_tokens(&self) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            DELETE FROM revoked_tokens
+            WHERE expires_at < NOW() - make_interval(secs => $1)
+            "#,
+        )
+        .bind(self.config.auth.jwt.clock_skew as f64)
+        .execute(&self.pg_pool)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+}
+
+// 32 random bytes in hex
+pub(super) fn generate_token() -> String {
+    let mut buf = [0u8; 32];
+    OsRng.fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(super) fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha1::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn refresh_token_should_rotate() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let token = state.create_refresh_token(1).await?;
+        assert_eq!(token.len(), 64);
+
+        let user_id = state.rotate_refresh_token(&token).await?;
+        assert_eq!(user_id, 1);
+
+        // a token could only be used once
+        let ret = state.rotate_refresh_token(&token).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+
+        let ret = state.rotate_refresh_token("unknown").await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_should_revoke_all() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let token = state.create_refresh_token(1).await?;
+        let other = state.create_refresh_token(1).await?;
+        state.rotate_refresh_token(&token).await?;
+
+        // reuse of rotated token revokes the other one as well
+        assert!(state.rotate_refresh_token(&token).await.is_err());
+        let ret = state.rotate_refresh_token(&other).await;
+        assert!(matches!(ret, Err(AppError::InvalidToken(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_token_should_be_revoked_by_owner() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let token = state.create_refresh_token(1).await?;
+        // not the owner, nothing happens
+        state.revoke_refresh_token(&token, 2).await?;
+        assert_eq!(state.rotate_refresh_token(&token).await?, 1);
+
+        let token = state.create_refresh_token(1).await?;
+        state.revoke_refresh_token(&token, 1).await?;
+        assert!(state.rotate_refresh_token(&token).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn access_token_should_be_revoked() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        assert!(!state.is_token_revoked("jti").await?);
+        let expires_at = Utc::now() + Duration::minutes(15);
+        state.revoke_token("jti", expires_at).await?;
+        // revoke twice should be fine
+        state.revoke_token("jti", expires_at).await?;
+        assert!(state.is_token_revoked("jti").await?);
+
+        // kept until expired beyond the clock skew
+        state
+            .revoke_token("expired", Utc::now() - Duration::hours(1))
+            .await?;
+        assert_eq!(state.purge_revoked_tokens().await?, 1);
+        assert!(state.is_token_revoked("jti").await?);
+        assert!(!state.is_token_revoked("expired").await?);
+        Ok(())
+    }
+}
@@ -1,20 +1,25 @@
 use std::mem;
 
+use super::invite::consume_invite;
 use crate::{AppError, AppState};
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use chat_core::{ChatUser, User};
+use chat_core::{User, WorkspaceRole};
 use serde::Deserialize;
-use sqlx::PgPool;
+use sqlx::PgConnection;
 
 #[derive(Deserialize)]
 pub struct CreateUser {
     pub email: String,
     pub password: String,
     pub fullname: String,
-    pub workspace: String,
+    // name of a new workspace to create, or an invite to join an existing one
+    #[serde(default)]
+    pub workspace: Option<String>,
+    #[serde(default)]
+    pub invite: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -37,23 +42,21 @@ impl AppState {
         Ok(user)
     }
 
-    pub async fn fetch_all_users(&self, ws_id: i32) -> Result<Vec<User>, AppError> {
-        let users = sqlx::query_as(
+    pub async fn find_user_by_id(&self, id: i64) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
             r#"
-            SELECT *
-            FROM users
-            WHERE ws_id = $1
+            SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1
             "#,
         )
-        .bind(ws_id)
-        .fetch_all(&self.pg_pool)
+        .bind(id)
+        .fetch_optional(&self.pg_pool)
         .await?;
 
-        Ok(users)
+        Ok(user)
     }
 
-    /// Create a new user
-    // TODO: use transaction for workspace creation and user creation
+    /// Create a new user, either joining a workspace by invite or owning a
+    /// brand-new workspace.
     pub async fn create_user(&self, input: &CreateUser) -> Result<User, AppError> {
         // check if email exists
         let user = self.find_user_by_email(&input.email).await?;
@@ -61,12 +64,37 @@ impl AppState {
             return Err(AppError::EmailAlreadyExists(input.email.clone()));
         }
 
-        // check if workspace exists, if not create one
-        let ws = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
+        let mut tx = self.pg_pool.begin().await?;
+        let (ws_id, is_new) = match (&input.invite, &input.workspace) {
+            (Some(invite), None) => (consume_invite(&mut tx, invite).await?, false),
+            (None, Some(name)) => {
+                if self.find_workspace_by_name(name).await?.is_some() {
+                    return Err(AppError::WorkspaceAlreadyExists(name.clone()));
+                }
+                let ws_id: i64 = sqlx::query_scalar(
+                    r#"
+                    INSERT INTO workspaces (name, owner_id)
+                    VALUES ($1, 0)
+                    RETURNING id
+                    "#,
+                )
+                .bind(name)
+                .fetch_one(&mut *tx)
+                .await?;
+                (ws_id, true)
+            }
+            _ => {
+                return Err(AppError::InviteError(
+                    "either invite or workspace is required".to_string(),
+                ))
+            }
         };
 
+        let role = if is_new {
+            WorkspaceRole::Owner
+        } else {
+            WorkspaceRole::Member
+        };
         let password_hash = hash_password(&input.password)?;
         let user: User = sqlx::query_as(
             r#"
@@ -75,17 +103,23 @@ impl AppState {
             RETURNING id, ws_id, fullname, email, created_at
             "#,
         )
-        .bind(ws.id)
+        .bind(ws_id)
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
-        .fetch_one(&self.pg_pool)
+        .fetch_one(&mut *tx)
         .await?;
-
-        if ws.owner_id == 0 {
-            self.update_workspace_owner(ws.id, user.id).await?;
+        add_member(&mut tx, ws_id, user.id, role).await?;
+
+        if is_new {
+            sqlx::query("UPDATE workspaces SET owner_id = $1 WHERE id = $2")
+                .bind(user.id)
+                .bind(ws_id)
+                .execute(&mut *tx)
+                .await?;
         }
 
+        tx.commit().await?;
         Ok(user)
     }
 
@@ -113,51 +147,43 @@ impl AppState {
         Ok(None)
     }
 
-    #[allow(dead_code)]
-    pub async fn add_to_workspace(
-        &self,
-        user_id: i64,
-        ws_id: i64,
-        pool: &PgPool,
-    ) -> Result<User, AppError> {
-        let user = sqlx::query_as(
+    /// Join another workspace by invite, the current workspace of user is unchanged
+    pub async fn add_to_workspace(&self, user_id: i64, invite: &str) -> Result<i64, AppError> {
+        let mut tx = self.pg_pool.begin().await?;
+        let ws_id = consume_invite(&mut tx, invite).await?;
+        if !add_member(&mut tx, ws_id, user_id, WorkspaceRole::Member).await? {
+            return Err(AppError::InviteError(format!(
+                "already a member of workspace {}",
+                ws_id
+            )));
+        }
+        tx.commit().await?;
+
+        Ok(ws_id)
+    }
+
+    /// Switch the current workspace of user, used to scope its tokens
+    pub async fn switch_workspace(&self, user_id: i64, ws_id: i64) -> Result<User, AppError> {
+        let user: Option<User> = sqlx::query_as(
             r#"
             UPDATE users
             SET ws_id = $1
             WHERE id = $2
+            AND EXISTS(SELECT 1 FROM workspace_members WHERE ws_id = $1 AND user_id = $2)
+            RETURNING id, ws_id, fullname, email, created_at
             "#,
         )
         .bind(ws_id)
         .bind(user_id)
-        .fetch_one(pool)
-        .await?;
-
-        Ok(user)
-    }
-
-    pub async fn fetch_chat_user_by_ids(&self, ids: &[i64]) -> Result<Vec<ChatUser>, AppError> {
-        let users = sqlx::query_as(r#"SELECT * FROM users WHERE id = ANY($1)"#)
-            .bind(ids)
-            .fetch_all(&self.pg_pool)
-            .await?;
-
-        Ok(users)
-    }
-
-    #[allow(dead_code)]
-    pub async fn fetch_chat_users(&self, ws_id: i64) -> Result<Vec<ChatUser>, AppError> {
-        let users = sqlx::query_as(
-            r#"
-            SELECT id, ws_id, fullname, email, created_at
-            FROM users
-            WHERE ws_id = $1
-            "#,
-        )
-        .bind(ws_id)
-        .fetch_all(&self.pg_pool)
+        .fetch_optional(&self.pg_pool)
         .await?;
 
-        Ok(users)
+        user.ok_or_else(|| {
+            AppError::NotWorkspaceMember(format!(
+                "user {} is not a member of workspace {}",
+                user_id, ws_id
+            ))
+        })
     }
 }
 
@@ -187,6 +213,29 @@ impl AppState {
 //     }
 // }
 
+// returns false if user is already a member
+async fn add_member(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    user_id: i64,
+    role: WorkspaceRole,
+) -> Result<bool, AppError> {
+    let ret = sqlx::query(
+        r#"
+        INSERT INTO workspace_members (ws_id, user_id, role)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (ws_id, user_id) DO NOTHING
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .bind(role)
+    .execute(conn)
+    .await?;
+
+    Ok(ret.rows_affected() > 0)
+}
+
 fn hash_password(password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
 
@@ -219,7 +268,18 @@ fn verify_password(password: &str, parsed_hash: &str) -> Result<bool, AppError>
 impl CreateUser {
     pub fn new(workspace: &str, fullname: &str, email: &str, password: &str) -> Self {
         Self {
-            workspace: workspace.to_string(),
+            workspace: Some(workspace.to_string()),
+            invite: None,
+            email: email.to_string(),
+            password: password.to_string(),
+            fullname: fullname.to_string(),
+        }
+    }
+
+    pub fn with_invite(invite: &str, fullname: &str, email: &str, password: &str) -> Self {
+        Self {
+            workspace: None,
+            invite: Some(invite.to_string()),
             email: email.to_string(),
             password: password.to_string(),
             fullname: fullname.to_string(),
@@ -278,11 +338,49 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_find_by_ids() -> Result<(), AppError> {
+    async fn test_user_create_should_own_new_workspace() -> Result<(), AppError> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        let input = CreateUser::new("newco", "wrxx", "wrxx@newco.org", "password");
+        let user = state.create_user(&input).await?;
+        let ws = state.find_workspace_by_name("newco").await?.unwrap();
+        assert_eq!(user.ws_id, ws.id);
+        assert_eq!(ws.owner_id, user.id);
+        assert_eq!(
+            state.get_member_role(ws.id, user.id).await?,
+            WorkspaceRole::Owner
+        );
+
+        // joining an existing workspace by name is not allowed
+        let input = CreateUser::new("acme", "wrxx", "wrxx@acme.org", "password");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(_))));
+
+        let mut input = CreateUser::new("other", "wrxx", "wrxx@other.org", "password");
+        input.workspace = None;
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_user_should_join_and_switch_workspace() -> Result<(), AppError> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let users = state.fetch_chat_user_by_ids(&[1, 2]).await?;
-        assert_eq!(users.len(), 2);
+        let ret = state.switch_workspace(2, 2).await;
+        assert!(matches!(ret, Err(AppError::NotWorkspaceMember(_))));
 
+        let invite = state
+            .create_invite(2, 1, &crate::models::CreateInvite::new(2, 60))
+            .await?;
+        assert_eq!(state.add_to_workspace(2, &invite.token).await?, 2);
+        let ret = state.add_to_workspace(2, &invite.token).await;
+        assert!(matches!(ret, Err(AppError::InviteError(_))));
+
+        let user = state.switch_workspace(2, 2).await?;
+        assert_eq!(user.ws_id, 2);
+        assert_eq!(state.find_user_by_id(2).await?.unwrap().ws_id, 2);
+        assert_eq!(state.get_member_role(2, 2).await?, WorkspaceRole::Member);
         Ok(())
     }
 }
@@ -1,8 +1,59 @@
-use chat_core::{ChatUser, Workspace};
+use chat_core::{ChatUser, Workspace, WorkspaceRole};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection};
 
 use crate::{AppError, AppState};
 
+const DEFAULT_USER_LIMIT: u64 = 20;
+const MAX_USER_LIMIT: u64 = 100;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ListUsers {
+    // prefix of fullname or email
+    pub q: Option<String>,
+    // fetch users after this one, None for the first page
+    pub last_id: Option<i64>,
+    #[serde(default = "default_user_limit")]
+    pub limit: u64,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceMember {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub role: WorkspaceRole,
+}
+
+// workspace the user belongs to, along with its role there
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MemberWorkspace {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub role: WorkspaceRole,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct JoinWorkspace {
+    pub invite: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct UpdateMember {
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TransferOwner {
+    pub user_id: i64,
+}
+
 impl AppState {
+    // workspaces are created on signup, see `create_user`
+    #[cfg(test)]
     pub async fn create_workspace(&self, name: &str, user_id: i64) -> Result<Workspace, AppError> {
         let ws = sqlx::query_as(
             r#"
@@ -19,45 +70,43 @@ impl AppState {
         Ok(ws)
     }
 
-    pub async fn update_workspace_owner(
+    /// Fetch a page of users in workspace ordered by id, optionally filtered by
+    /// name or email prefix. Use the id of the last user as `last_id` for next page.
+    pub async fn fetch_all_chat_users(
         &self,
         ws_id: i64,
-        owner_id: i64,
-    ) -> Result<Workspace, AppError> {
-        // update owner_id in two cases 1) owner_id = 0 2) owner's ws_id = id
-        let ws = sqlx::query_as(
-            r#"
-        UPDATE workspaces
-        SET owner_id = $1
-        WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
-        RETURNING id, name, owner_id, created_at
-        "#,
-        )
-        .bind(owner_id)
-        .bind(ws_id)
-        .fetch_one(&self.pg_pool)
-        .await?;
-
-        Ok(ws)
-    }
+        input: &ListUsers,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        let limit = input.limit.clamp(1, MAX_USER_LIMIT);
+        let prefix = input
+            .q
+            .as_deref()
+            .map(str::trim)
+            .filter(|q| !q.is_empty())
+            .map(|q| format!("{}%", escape_like(q)));
 
-    #[allow(unused)]
-    pub async fn fetch_all_chat_users(&self, ws_id: i64) -> Result<Vec<ChatUser>, AppError> {
         let users: Vec<ChatUser> = sqlx::query_as(
             r#"
-            SELECT id, fullname, email
-            FROM users
-            WHERE ws_id = $1 order by id
+            SELECT u.id, u.fullname, u.email
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id
+            WHERE m.ws_id = $1
+            AND ($2::TEXT IS NULL OR u.fullname ILIKE $2 OR u.email ILIKE $2)
+            AND ($3::BIGINT IS NULL OR u.id > $3)
+            ORDER BY u.id
+            LIMIT $4
             "#,
         )
         .bind(ws_id)
+        .bind(prefix)
+        .bind(input.last_id)
+        .bind(limit as i64)
         .fetch_all(&self.pg_pool)
         .await?;
 
         Ok(users)
     }
 
-    #[allow(unused)]
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws: Option<Workspace> = sqlx::query_as(
             r#"
@@ -73,7 +122,6 @@ impl AppState {
         Ok(ws)
     }
 
-    #[allow(unused)]
     pub async fn find_by_id(&self, id: i64) -> Result<Option<Workspace>, AppError> {
         let ws: Option<Workspace> = sqlx::query_as(
             r#"
@@ -88,26 +136,319 @@ impl AppState {
 
         Ok(ws)
     }
+
+    pub async fn fetch_user_workspaces(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<MemberWorkspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, m.role, w.created_at
+            FROM workspaces w
+            JOIN workspace_members m ON m.ws_id = w.id
+            WHERE m.user_id = $1
+            ORDER BY w.id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pg_pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    pub async fn find_member(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<Option<WorkspaceMember>, AppError> {
+        let member = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email, m.role
+            FROM users u
+            JOIN workspace_members m ON m.user_id = u.id
+            WHERE u.id = $1 AND m.ws_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .fetch_optional(&self.pg_pool)
+        .await?;
+
+        Ok(member)
+    }
+
+    /// Role of user in the workspace, error if user is not a member
+    pub async fn get_member_role(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<WorkspaceRole, AppError> {
+        match self.find_member(ws_id, user_id).await? {
+            Some(member) => Ok(member.role),
+            None => Err(AppError::NotWorkspaceMember(format!(
+                "user {} is not a member of workspace {}",
+                user_id, ws_id
+            ))),
+        }
+    }
+
+    /// Promote or demote a member, only the owner could grant or revoke admin
+    pub async fn update_member_role(
+        &self,
+        ws_id: i64,
+        actor_id: i64,
+        user_id: i64,
+        role: WorkspaceRole,
+    ) -> Result<WorkspaceMember, AppError> {
+        if role == WorkspaceRole::Owner {
+            return Err(AppError::PermissionDenied(
+                "transfer the ownership instead".to_string(),
+            ));
+        }
+
+        let actor = self.get_member_role(ws_id, actor_id).await?;
+        let target = self.find_target_member(ws_id, user_id).await?;
+        check_manage(actor, target.role)?;
+        if actor != WorkspaceRole::Owner && role == WorkspaceRole::Admin {
+            return Err(AppError::PermissionDenied(
+                "only the owner could grant admin".to_string(),
+            ));
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET role = $1
+            WHERE user_id = $2 AND ws_id = $3
+            "#,
+        )
+        .bind(role)
+        .bind(user_id)
+        .bind(ws_id)
+        .execute(&self.pg_pool)
+        .await?;
+
+        Ok(WorkspaceMember { role, ..target })
+    }
+
+    /// Remove a member from the workspace and its channels. If it's the user's
+    /// current workspace, the user is moved to another one it belongs to, and
+    /// its refresh tokens are revoked.
+    pub async fn remove_member(
+        &self,
+        ws_id: i64,
+        actor_id: i64,
+        user_id: i64,
+    ) -> Result<(), AppError> {
+        let actor = self.get_member_role(ws_id, actor_id).await?;
+        let target = self.find_target_member(ws_id, user_id).await?;
+        check_manage(actor, target.role)?;
+
+        let mut tx = self.pg_pool.begin().await?;
+        // type of single and group chats depends on members, and channels need
+        // at least 2 members, so those keep the user who could no longer access them
+        sqlx::query(
+            r#"
+            UPDATE chats
+            SET members = array_remove(members, $1)
+            WHERE ws_id = $2 AND $1 = ANY(members)
+            AND type IN ('public_channel', 'private_channel') AND cardinality(members) > 2
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM workspace_members WHERE ws_id = $1 AND user_id = $2")
+            .bind(ws_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        // fallback to the default workspace 0 if the user belongs to none
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET ws_id = COALESCE(
+                (SELECT ws_id FROM workspace_members WHERE user_id = $1 ORDER BY created_at LIMIT 1),
+                0)
+            WHERE id = $1 AND ws_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE refresh_tokens
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Transfer the ownership to another member, the previous owner becomes an admin
+    pub async fn transfer_owner(
+        &self,
+        ws_id: i64,
+        actor_id: i64,
+        user_id: i64,
+    ) -> Result<Workspace, AppError> {
+        let mut tx = self.pg_pool.begin().await?;
+        match lock_member_role(&mut tx, ws_id, actor_id).await? {
+            Some(WorkspaceRole::Owner) => {}
+            Some(_) => {
+                return Err(AppError::PermissionDenied(
+                    "only the owner could transfer the ownership".to_string(),
+                ))
+            }
+            None => {
+                return Err(AppError::NotWorkspaceMember(format!(
+                    "user {} is not a member of workspace {}",
+                    actor_id, ws_id
+                )))
+            }
+        }
+        if lock_member_role(&mut tx, ws_id, user_id).await?.is_none() {
+            return Err(AppError::NotFound(format!(
+                "member {} of workspace {}",
+                user_id, ws_id
+            )));
+        }
+
+        let ws = update_workspace_owner(&mut tx, ws_id, user_id).await?;
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET role = CASE WHEN user_id = $1 THEN 'owner'::workspace_role ELSE 'admin'::workspace_role END
+            WHERE ws_id = $3 AND user_id IN ($1, $2)
+            "#,
+        )
+        .bind(user_id)
+        .bind(actor_id)
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(ws)
+    }
+
+    async fn find_target_member(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<WorkspaceMember, AppError> {
+        self.find_member(ws_id, user_id)
+            .await?
+            .ok_or_else(|| AppError::NotFound(format!("member {} of workspace {}", user_id, ws_id)))
+    }
+}
+
+// role of a member, locked until the end of the transaction
+async fn lock_member_role(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    user_id: i64,
+) -> Result<Option<WorkspaceRole>, AppError> {
+    let role = sqlx::query_scalar(
+        r#"
+        SELECT role FROM workspace_members
+        WHERE ws_id = $1 AND user_id = $2
+        FOR UPDATE
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(role)
+}
+
+// the new owner must be a member of the workspace
+async fn update_workspace_owner(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    owner_id: i64,
+) -> Result<Workspace, AppError> {
+    let ws = sqlx::query_as(
+        r#"
+        UPDATE workspaces
+        SET owner_id = $1
+        WHERE id = $2
+        AND EXISTS(SELECT 1 FROM workspace_members WHERE user_id = $1 AND ws_id = $2)
+        RETURNING id, name, owner_id, created_at
+        "#,
+    )
+    .bind(owner_id)
+    .bind(ws_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(ws)
+}
+
+fn default_user_limit() -> u64 {
+    DEFAULT_USER_LIMIT
+}
+
+// treat wildcards in user input literally
+fn escape_like(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// owner manages everyone else, admins manage members and guests
+fn check_manage(actor: WorkspaceRole, target: WorkspaceRole) -> Result<(), AppError> {
+    let allowed = match actor {
+        WorkspaceRole::Owner => target != WorkspaceRole::Owner,
+        WorkspaceRole::Admin => !target.is_admin(),
+        _ => false,
+    };
+
+    if !allowed {
+        return Err(AppError::PermissionDenied(format!(
+            "{:?} could not manage {:?}",
+            actor, target
+        )));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::models::CreateUser;
+    use crate::models::{CreateInvite, CreateUser};
     use anyhow::Result;
+    use chat_core::ChatType;
 
     #[tokio::test]
     async fn workspace_should_create_and_set_owner() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let ws = state.create_workspace("test", 0).await?;
+        assert_eq!(ws.name, "test");
 
-        let input = CreateUser::new(&ws.name, "wrx", "wrxx@qq.com", "wrxx");
+        let invite = state
+            .create_invite(ws.id, 0, &CreateInvite::new(1, 60))
+            .await?;
+        let input = CreateUser::with_invite(&invite.token, "wrx", "wrxx@qq.com", "wrxx");
         let user = state.create_user(&input).await?;
-
-        assert_eq!(ws.name, "test");
         assert_eq!(user.ws_id, ws.id);
 
-        let ws = state.update_workspace_owner(ws.id, user.id).await?;
+        let mut conn = state.pg_pool.acquire().await?;
+        let ws = update_workspace_owner(&mut conn, ws.id, user.id).await?;
         assert_eq!(ws.owner_id, user.id);
         Ok(())
     }
@@ -120,12 +461,160 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn workspace_should_fetch_user_workspaces() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let workspaces = state.fetch_user_workspaces(1).await?;
+        assert_eq!(workspaces.len(), 2);
+        assert_eq!(workspaces[0].name, "acme");
+        assert_eq!(workspaces[0].role, WorkspaceRole::Owner);
+        assert_eq!(workspaces[1].name, "foo");
+        assert_eq!(workspaces[1].role, WorkspaceRole::Member);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_should_search_chat_users() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let search = |q: &str| ListUsers {
+            q: Some(q.to_string()),
+            ..serde_json::from_str("{}").unwrap()
+        };
+
+        let users = state.fetch_all_chat_users(1, &search("al")).await?;
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].email, "alice@acme.org");
+
+        let users = state.fetch_all_chat_users(1, &search("BOB@")).await?;
+        assert_eq!(users.len(), 1);
+
+        // prefix only, and wildcards are literal
+        assert!(state
+            .fetch_all_chat_users(1, &search("chen"))
+            .await?
+            .is_empty());
+        assert!(state
+            .fetch_all_chat_users(1, &search("%"))
+            .await?
+            .is_empty());
+        assert_eq!(state.fetch_all_chat_users(1, &search(" ")).await?.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn member_role_should_be_updated_by_admins() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert_eq!(state.get_member_role(1, 1).await?, WorkspaceRole::Owner);
+        assert!(matches!(
+            state.get_member_role(3, 1).await,
+            Err(AppError::NotWorkspaceMember(_))
+        ));
+
+        // owner promotes bob to admin
+        let member = state
+            .update_member_role(1, 1, 3, WorkspaceRole::Admin)
+            .await?;
+        assert_eq!(member.role, WorkspaceRole::Admin);
+
+        // admin could not manage other admins or grant admin
+        let ret = state
+            .update_member_role(1, 2, 3, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_member_role(1, 2, 4, WorkspaceRole::Admin)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        // admin demotes charlie to guest
+        let member = state
+            .update_member_role(1, 2, 4, WorkspaceRole::Guest)
+            .await?;
+        assert_eq!(member.role, WorkspaceRole::Guest);
+
+        // members could not manage anyone, nobody could manage the owner
+        let ret = state
+            .update_member_role(1, 4, 5, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_member_role(1, 2, 1, WorkspaceRole::Member)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state
+            .update_member_role(1, 1, 2, WorkspaceRole::Owner)
+            .await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn member_should_be_removed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = state.create_refresh_token(3).await?;
+
+        let ret = state.remove_member(1, 4, 3).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+
+        state.remove_member(1, 2, 3).await?;
+        assert!(state.find_member(1, 3).await?.is_none());
+        let chat = state.find_chat_by_id(1).await?.unwrap();
+        assert!(!chat.members.contains(&3));
+        assert!(state.rotate_refresh_token(&token).await.is_err());
+
+        // chats are kept valid: group chat 4 and single chat 3 are untouched,
+        // private channel 2 is left with 2 members
+        let chat = state.find_chat_by_id(4).await?.unwrap();
+        assert_eq!(chat.members, vec![1, 3, 4]);
+        assert_eq!(chat.r#type, ChatType::Group);
+
+        let ret = state.remove_member(1, 2, 3).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        state.remove_member(1, 1, 2).await?;
+        let chat = state.find_chat_by_id(3).await?.unwrap();
+        assert_eq!(chat.members, vec![1, 2]);
+        assert_eq!(chat.r#type, ChatType::Single);
+        let chat = state.find_chat_by_id(2).await?.unwrap();
+        assert_eq!(chat.members, vec![1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn owner_should_be_transferred() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state.transfer_owner(1, 2, 3).await;
+        assert!(matches!(ret, Err(AppError::PermissionDenied(_))));
+        let ret = state.transfer_owner(1, 1, 99).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        assert_eq!(state.get_member_role(1, 1).await?, WorkspaceRole::Owner);
+
+        let ws = state.transfer_owner(1, 1, 3).await?;
+        assert_eq!(ws.owner_id, 3);
+        assert_eq!(state.get_member_role(1, 3).await?, WorkspaceRole::Owner);
+        assert_eq!(state.get_member_role(1, 1).await?, WorkspaceRole::Admin);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn workspace_should_fetch_all_chat_users() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let users = state.fetch_all_chat_users(1).await?;
+        let input: ListUsers = serde_json::from_str("{}")?;
+        let users = state.fetch_all_chat_users(1, &input).await?;
         assert_eq!(users.len(), 5);
 
+        let input = ListUsers {
+            last_id: Some(2),
+            limit: 2,
+            ..input
+        };
+        let users = state.fetch_all_chat_users(1, &input).await?;
+        assert_eq!(users.iter().map(|u| u.id).collect::<Vec<_>>(), vec![3, 4]);
+
+        // users of other workspaces are excluded
+        let users = state.fetch_all_chat_users(3, &input).await?;
+        assert!(users.is_empty());
+
         Ok(())
     }
 }
@@ -0,0 +1,112 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+use tokio_util::io::ReaderStream;
+
+use super::{FileStore, FileStream};
+use crate::AppError;
+
+/// Store files on local disk under `base_dir`
+pub struct LocalStore {
+    base_dir: PathBuf,
+}
+
+impl LocalStore {
+    pub fn try_new(base_dir: impl AsRef<Path>) -> Result<Self, AppError> {
+        let base_dir = base_dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&base_dir)?;
+        Ok(Self { base_dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.base_dir.join(key)
+    }
+}
+
+#[async_trait]
+impl FileStore for LocalStore {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::write(path, data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, AppError> {
+        match fs::read(self.path(key)).await {
+            Ok(data) => Ok(data.into()),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(AppError::NotFound(format!("file {} not found", key)))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+        match fs::metadata(self.path(key)).await {
+            Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match fs::remove_file(self.path(key)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<FileStream, AppError> {
+        let mut file = match fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(AppError::NotFound(format!("file {} not found", key)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let stream = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start)).await?;
+                let reader = file.take(range.end.saturating_sub(range.start));
+                ReaderStream::new(reader).boxed()
+            }
+            None => ReaderStream::new(file).boxed(),
+        };
+
+        Ok(stream.map_err(AppError::from).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::file_store_should_work;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn local_store_should_work() -> Result<()> {
+        let base_dir = std::env::temp_dir().join(format!("chat_store_{}", std::process::id()));
+        let store = LocalStore::try_new(&base_dir)?;
+
+        file_store_should_work(&store).await?;
+
+        fs::remove_dir_all(base_dir).await?;
+        Ok(())
+    }
+}
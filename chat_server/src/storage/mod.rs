@@ -0,0 +1,82 @@
+mod local;
+mod s3;
+
+use std::ops::Range;
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::stream::BoxStream;
+
+use crate::{AppError, StorageConfig};
+
+pub use local::LocalStore;
+pub use s3::S3Store;
+
+pub type FileStream = BoxStream<'static, Result<Bytes, AppError>>;
+
+/// Storage backend for chat files. Keys are relative paths generated by
+/// `ChatFile::key`, e.g. `1/aaf/4c6/1ddcc5e8a2dabede0f3b482cd9aea9434d.txt`.
+#[async_trait]
+pub trait FileStore: Send + Sync + 'static {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError>;
+
+    #[allow(unused)]
+    async fn get(&self, key: &str) -> Result<Bytes, AppError>;
+
+    /// Size of the file in bytes, None if the file does not exist
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError>;
+
+    async fn delete(&self, key: &str) -> Result<(), AppError>;
+
+    /// Stream the content of the file, optionally limited to the given byte range
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<FileStream, AppError>;
+
+    async fn exists(&self, key: &str) -> Result<bool, AppError> {
+        Ok(self.size(key).await?.is_some())
+    }
+}
+
+pub fn new_store(config: &StorageConfig) -> Result<Box<dyn FileStore>, AppError> {
+    let store: Box<dyn FileStore> = match config {
+        StorageConfig::Local { base_dir } => Box::new(LocalStore::try_new(base_dir)?),
+        StorageConfig::S3(config) => Box::new(S3Store::try_new(config)?),
+    };
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use futures::TryStreamExt;
+
+    async fn collect(stream: FileStream) -> Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = stream.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    // shared behavior every store should satisfy
+    pub(crate) async fn file_store_should_work(store: &dyn FileStore) -> Result<()> {
+        let key = "1/aaa/bbb/ccc.txt";
+        let data = Bytes::from_static(b"hello world");
+
+        assert!(!store.exists(key).await?);
+        assert_eq!(store.size(key).await?, None);
+        assert!(matches!(store.get(key).await, Err(AppError::NotFound(_))));
+
+        store.put(key, data.clone()).await?;
+        assert!(store.exists(key).await?);
+        assert_eq!(store.size(key).await?, Some(data.len() as u64));
+        assert_eq!(store.get(key).await?, data);
+
+        let content = collect(store.stream(key, None).await?).await?;
+        assert_eq!(content, b"hello world");
+        let content = collect(store.stream(key, Some(6..11)).await?).await?;
+        assert_eq!(content, b"world");
+
+        store.delete(key).await?;
+        assert!(!store.exists(key).await?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::{ops::Range, sync::Arc};
+
+use async_trait::async_trait;
+use axum::body::Bytes;
+use futures::{StreamExt, TryStreamExt};
+use object_store::{
+    aws::AmazonS3Builder, path::Path, GetOptions, GetRange, ObjectStore, PutPayload,
+};
+
+use super::{FileStore, FileStream};
+use crate::{AppError, S3Config};
+
+/// Store files in an S3 compatible object storage (AWS S3, MinIO, etc.)
+pub struct S3Store {
+    inner: Arc<dyn ObjectStore>,
+}
+
+impl S3Store {
+    pub fn try_new(config: &S3Config) -> Result<Self, AppError> {
+        let store = AmazonS3Builder::new()
+            .with_endpoint(&config.endpoint)
+            .with_region(&config.region)
+            .with_bucket_name(&config.bucket)
+            .with_access_key_id(&config.access_key)
+            .with_secret_access_key(&config.secret_key)
+            .with_allow_http(config.endpoint.starts_with("http://"))
+            .build()?;
+
+        Ok(Self::from_object_store(Arc::new(store)))
+    }
+
+    /// Build from any object store, this is mainly for testing with a stand-in store
+    pub fn from_object_store(inner: Arc<dyn ObjectStore>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl FileStore for S3Store {
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), AppError> {
+        self.inner
+            .put(&Path::from(key), PutPayload::from_bytes(data))
+            .await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, AppError> {
+        let ret = self.inner.get(&Path::from(key)).await?;
+        Ok(ret.bytes().await?)
+    }
+
+    async fn size(&self, key: &str) -> Result<Option<u64>, AppError> {
+        match self.inner.head(&Path::from(key)).await {
+            Ok(meta) => Ok(Some(meta.size as u64)),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AppError> {
+        match self.inner.delete(&Path::from(key)).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn stream(&self, key: &str, range: Option<Range<u64>>) -> Result<FileStream, AppError> {
+        let options = GetOptions {
+            range: range.map(|r| GetRange::Bounded(r.start as usize..r.end as usize)),
+            ..Default::default()
+        };
+        let ret = self.inner.get_opts(&Path::from(key), options).await?;
+        Ok(ret.into_stream().map_err(AppError::from).boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::file_store_should_work;
+    use anyhow::Result;
+    use object_store::memory::InMemory;
+
+    #[tokio::test]
+    async fn s3_store_should_work() -> Result<()> {
+        let store = S3Store::from_object_store(Arc::new(InMemory::new()));
+        file_store_should_work(&store).await
+    }
+}
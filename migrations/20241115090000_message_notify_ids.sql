@@ -0,0 +1,16 @@
+-- Add migration script here
+-- pg_notify payloads are limited to 8000 bytes, which a long message plus its
+-- chat could exceed, so only the message id is sent and notify_server loads the rest
+CREATE OR REPLACE FUNCTION add_to_message()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+      RAISE NOTICE 'add_to_message: %', NEW.id;
+        PERFORM pg_notify(
+            'chat_message_created',
+            json_build_object('message_id', NEW.id)::TEXT
+        );
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- add files for messages, stores urls of uploaded chat files
+ALTER TABLE messages
+ADD COLUMN files TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,24 @@
+-- Add migration script here
+-- refresh tokens issued to users, only sha1 hash of the token is stored
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    token_hash VARCHAR(40) NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    -- set when the token is used (rotated) or user signs out
+    revoked_at TIMESTAMPTZ,
+    -- set only when the token is used, presenting it again means it's leaked
+    rotated_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index of refresh tokens for user_id
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_index ON refresh_tokens(user_id);
+
+-- access tokens revoked before expiration, identified by jwt id (jti)
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+    jti VARCHAR(64) PRIMARY KEY,
+    -- the token could be purged after it expires
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
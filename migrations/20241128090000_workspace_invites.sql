@@ -0,0 +1,15 @@
+-- Add migration script here
+-- invitations to join a workspace, only sha1 hash of the token is stored
+CREATE TABLE IF NOT EXISTS workspace_invites (
+    id BIGSERIAL PRIMARY KEY,
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    created_by BIGINT NOT NULL REFERENCES users(id),
+    token_hash VARCHAR(40) NOT NULL UNIQUE,
+    max_uses INT NOT NULL DEFAULT 1,
+    used_count INT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+-- create index of invites for ws_id
+CREATE INDEX IF NOT EXISTS workspace_invites_ws_id_index ON workspace_invites(ws_id);
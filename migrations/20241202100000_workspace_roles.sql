@@ -0,0 +1,11 @@
+-- Add migration script here
+-- role of a user in its workspace
+CREATE TYPE workspace_role AS ENUM ('owner', 'admin', 'member', 'guest');
+
+ALTER TABLE users
+ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
+
+-- existing workspace owners
+UPDATE users
+SET role = 'owner'
+WHERE id IN (SELECT owner_id FROM workspaces WHERE id = users.ws_id);
@@ -0,0 +1,18 @@
+-- Add migration script here
+-- users could join multiple workspaces, users.ws_id is the one currently in use
+CREATE TABLE IF NOT EXISTS workspace_members (
+    ws_id BIGINT NOT NULL REFERENCES workspaces(id),
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    role workspace_role NOT NULL DEFAULT 'member',
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (ws_id, user_id)
+);
+
+-- create index of members for user_id
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_index ON workspace_members(user_id);
+
+-- move existing memberships, role is now kept per workspace
+INSERT INTO workspace_members (ws_id, user_id, role)
+SELECT ws_id, id, role FROM users WHERE id <> 0;
+
+ALTER TABLE users DROP COLUMN role;
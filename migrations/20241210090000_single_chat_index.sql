@@ -0,0 +1,25 @@
+-- Add migration script here
+-- merge duplicated single chats into the oldest one, moving their messages
+CREATE TEMP TABLE single_chat_dups ON COMMIT DROP AS
+SELECT id, keep_id FROM (
+    SELECT id, min(id) OVER (
+        PARTITION BY ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2])
+    ) AS keep_id
+    FROM chats
+    WHERE type = 'single'
+) c
+WHERE id <> keep_id;
+
+UPDATE messages m
+SET chat_id = d.keep_id
+FROM single_chat_dups d
+WHERE m.chat_id = d.id;
+
+DELETE FROM chats c
+USING single_chat_dups d
+WHERE c.id = d.id;
+
+-- only one direct (single) chat for the same pair of users in a workspace
+CREATE UNIQUE INDEX IF NOT EXISTS chats_single_members_index
+ON chats (ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2]))
+WHERE type = 'single';
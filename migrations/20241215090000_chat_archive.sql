@@ -0,0 +1,18 @@
+-- Add migration script here
+-- archived chats are read-only and hidden by default, deleted chats are
+-- invisible and purged after the retention window
+ALTER TABLE chats
+ADD COLUMN archived_at TIMESTAMPTZ,
+ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- deleted direct chats should not block a new one
+DROP INDEX IF EXISTS chats_single_members_index;
+CREATE UNIQUE INDEX IF NOT EXISTS chats_single_members_index
+ON chats (ws_id, LEAST(members[1], members[2]), GREATEST(members[1], members[2]))
+WHERE type = 'single' AND deleted_at IS NULL;
+
+-- messages go with the chat when it's purged
+ALTER TABLE messages
+DROP CONSTRAINT IF EXISTS messages_chat_id_fkey,
+ADD CONSTRAINT messages_chat_id_fkey
+FOREIGN KEY (chat_id) REFERENCES chats(id) ON DELETE CASCADE;
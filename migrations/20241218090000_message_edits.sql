@@ -0,0 +1,42 @@
+-- Add migration script here
+-- deleted messages are kept as tombstones so that pagination is stable
+ALTER TABLE messages
+ADD COLUMN edited_at TIMESTAMPTZ,
+ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- previous versions of edited messages
+CREATE TABLE IF NOT EXISTS message_edits (
+    id BIGSERIAL PRIMARY KEY,
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    content TEXT NOT NULL,
+    files TEXT[] DEFAULT '{}',
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_index ON message_edits(message_id);
+
+-- notify on new messages, and on messages edited or deleted, with the message
+-- id only like before
+CREATE OR REPLACE FUNCTION add_to_message()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+      RAISE NOTICE 'add_to_message: %', NEW.id;
+        PERFORM pg_notify(
+            'chat_message_created',
+            json_build_object('message_id', NEW.id)::TEXT
+        );
+    ELSIF TG_OP = 'UPDATE' AND (
+      NEW.content IS DISTINCT FROM OLD.content
+      OR NEW.files IS DISTINCT FROM OLD.files
+      OR NEW.deleted_at IS DISTINCT FROM OLD.deleted_at
+    ) THEN
+      RAISE NOTICE 'update_message: %', NEW.id;
+        PERFORM pg_notify(
+            'chat_message_updated',
+            json_build_object('message_id', NEW.id)::TEXT
+        );
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- replies belong to the thread of their root message, nested threads are flattened
+ALTER TABLE messages
+ADD COLUMN thread_root_id BIGINT REFERENCES messages(id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS messages_thread_root_id_index ON messages(thread_root_id, created_at DESC)
+WHERE thread_root_id IS NOT NULL;
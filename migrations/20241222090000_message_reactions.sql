@@ -0,0 +1,45 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS message_reactions (
+    message_id BIGINT NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    emoji VARCHAR(64) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (message_id, emoji, user_id)
+);
+
+-- if a reaction is added or removed, notify with reaction and chat data
+CREATE OR REPLACE FUNCTION message_reaction_changed()
+RETURNS TRIGGER AS $$
+DECLARE
+    reaction RECORD;
+    chat_record RECORD;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+      reaction := NEW;
+    ELSE
+      reaction := OLD;
+    END IF;
+    SELECT c.* INTO chat_record
+    FROM chats c JOIN messages m ON m.chat_id = c.id
+    WHERE m.id = reaction.message_id;
+    -- chat is being purged
+    IF NOT FOUND OR chat_record.deleted_at IS NOT NULL THEN
+      RETURN NULL;
+    END IF;
+    RAISE NOTICE 'message_reaction_changed: %', reaction;
+    PERFORM pg_notify(
+        'message_reaction_changed',
+        json_build_object(
+          'op', TG_OP,
+          'reaction', reaction,
+          'chat', chat_record
+        )::TEXT
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER message_reaction_changed_trigger
+AFTER INSERT OR DELETE ON message_reactions
+FOR EACH ROW
+EXECUTE FUNCTION message_reaction_changed();
@@ -0,0 +1,36 @@
+-- Add migration script here
+-- last message read by user in a chat, message ids only grow so they mark the position
+CREATE TABLE IF NOT EXISTS chat_reads (
+    chat_id BIGINT NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id BIGINT NOT NULL REFERENCES users(id),
+    last_read_message_id BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+-- if user read more of a chat, notify with read and chat data
+CREATE OR REPLACE FUNCTION chat_read_changed()
+RETURNS TRIGGER AS $$
+DECLARE
+    chat_record RECORD;
+BEGIN
+    IF TG_OP = 'UPDATE' AND NEW.last_read_message_id = OLD.last_read_message_id THEN
+      RETURN NULL;
+    END IF;
+    SELECT * INTO chat_record FROM chats WHERE id = NEW.chat_id;
+    RAISE NOTICE 'chat_read_changed: %', NEW;
+    PERFORM pg_notify(
+        'chat_read',
+        json_build_object(
+          'read', NEW,
+          'chat', chat_record
+        )::TEXT
+    );
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER chat_read_changed_trigger
+AFTER INSERT OR UPDATE ON chat_reads
+FOR EACH ROW
+EXECUTE FUNCTION chat_read_changed();
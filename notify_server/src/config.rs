@@ -1,4 +1,5 @@
 use anyhow::Result;
+use chat_core::utils::JwtConfig;
 use serde::Deserialize;
 use serde_yaml::from_reader;
 use std::fs;
@@ -9,9 +10,19 @@ pub struct AppConfig {
     pub auth: AuthConfig,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct AuthConfig {
-    pub pk: String,
+    // static public key, not needed if jwks_url is set
+    #[serde(default)]
+    pub pk: Option<String>,
+    #[serde(default)]
+    pub previous_pks: Vec<String>,
+    // fetch public keys from chat_server, e.g. http://localhost:4578/.well-known/jwks.json
+    #[serde(default)]
+    pub jwks_url: Option<String>,
+    // issuer, audience, clock skew etc. for access tokens
+    #[serde(flatten)]
+    pub jwt: JwtConfig,
 }
 
 #[derive(Debug, Deserialize)]
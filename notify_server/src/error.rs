@@ -9,6 +9,21 @@ pub enum AppError {
 
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
+
+    #[error("sqlx error: {0}")]
+    SqlxError(#[from] sqlx::Error),
+
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+
+    #[error("jwks error: {0}")]
+    JwksError(String),
+
+    #[error("not chat member: {0}")]
+    NotChatMember(String),
+
+    #[error("too many requests: {0}")]
+    TooManyRequests(String),
 }
 
 impl IntoResponse for AppError {
@@ -16,6 +31,11 @@ impl IntoResponse for AppError {
         let status = match self {
             AppError::StdError(_) => status::StatusCode::INTERNAL_SERVER_ERROR,
             AppError::JwtError(_) => status::StatusCode::FORBIDDEN,
+            AppError::SqlxError(_) => status::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::InvalidToken(_) => status::StatusCode::UNAUTHORIZED,
+            AppError::JwksError(_) => status::StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::NotChatMember(_) => status::StatusCode::FORBIDDEN,
+            AppError::TooManyRequests(_) => status::StatusCode::TOO_MANY_REQUESTS,
         };
 
         (
@@ -0,0 +1,134 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use chat_core::{
+    utils::{DecodingKey, JwkSet},
+    User,
+};
+use jwt_simple::{claims::JWTClaims, JWTError};
+use tracing::{info, warn};
+
+use crate::{config::AuthConfig, AppError};
+
+// don't hit chat_server more than once per interval for unknown key ids
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Keys to verify access tokens, either static ones from config or fetched
+/// from chat_server's jwks endpoint and refreshed when keys are rotated.
+pub struct Keyset {
+    auth: AuthConfig,
+    dk: RwLock<Arc<DecodingKey>>,
+    refreshed_at: Mutex<Instant>,
+}
+
+impl Keyset {
+    pub async fn load(auth: AuthConfig) -> Result<Self, AppError> {
+        let dk = match (&auth.jwks_url, &auth.pk) {
+            (Some(url), _) => fetch_jwks(url).await?,
+            (None, Some(pk)) => DecodingKey::load_keyset(
+                std::iter::once(pk.as_str()).chain(auth.previous_pks.iter().map(|pk| pk.as_str())),
+            )?,
+            (None, None) => {
+                return Err(AppError::JwksError(
+                    "either pk or jwks_url is required".to_string(),
+                ))
+            }
+        };
+
+        Ok(Self {
+            dk: RwLock::new(Arc::new(dk.with_config(auth.jwt.clone()))),
+            refreshed_at: Mutex::new(Instant::now()),
+            auth,
+        })
+    }
+
+    pub async fn verify_claims(&self, token: &str) -> Result<JWTClaims<User>, AppError> {
+        match self.current().verify_claims(token) {
+            Err(e) if is_unknown_key(&e) && self.refresh().await? => {
+                Ok(self.current().verify_claims(token)?)
+            }
+            ret => Ok(ret?),
+        }
+    }
+
+    fn current(&self) -> Arc<DecodingKey> {
+        self.dk.read().expect("keyset lock poisoned").clone()
+    }
+
+    // returns false if keys are static or refreshed recently
+    async fn refresh(&self) -> Result<bool, AppError> {
+        let Some(url) = &self.auth.jwks_url else {
+            return Ok(false);
+        };
+
+        {
+            let mut refreshed_at = self.refreshed_at.lock().expect("keyset lock poisoned");
+            if refreshed_at.elapsed() < JWKS_REFRESH_INTERVAL {
+                return Ok(false);
+            }
+            *refreshed_at = Instant::now();
+        }
+
+        let dk = fetch_jwks(url).await?.with_config(self.auth.jwt.clone());
+        *self.dk.write().expect("keyset lock poisoned") = Arc::new(dk);
+        info!("keyset refreshed from {}", url);
+        Ok(true)
+    }
+}
+
+async fn fetch_jwks(url: &str) -> Result<DecodingKey, AppError> {
+    let jwks: JwkSet = reqwest::get(url)
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| AppError::JwksError(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| AppError::JwksError(e.to_string()))?;
+
+    DecodingKey::from_jwks(&jwks).map_err(|e| {
+        warn!("invalid jwks from {}: {}", url, e);
+        AppError::JwksError(e.to_string())
+    })
+}
+
+fn is_unknown_key(e: &jwt_simple::Error) -> bool {
+    matches!(
+        e.downcast_ref::<JWTError>(),
+        Some(JWTError::KeyIdentifierMismatch)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::utils::EncodingKey;
+
+    fn auth_config(pk: Option<&str>) -> AuthConfig {
+        AuthConfig {
+            pk: pk.map(|pk| pk.to_string()),
+            previous_pks: vec![],
+            jwks_url: None,
+            jwt: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn keyset_should_load_static_keys() -> anyhow::Result<()> {
+        let ek = EncodingKey::load(include_str!("../../chat_core/fixtures/encoding.pem"))?;
+        let pk = include_str!("../../chat_core/fixtures/decoding.pem");
+        let keyset = Keyset::load(auth_config(Some(pk))).await?;
+
+        let user = User::new(1, "test", "test@qq.com");
+        let token = ek.sign(user.clone())?;
+        assert_eq!(keyset.verify_claims(&token).await?.custom, user);
+
+        // static keys are never refreshed
+        assert!(!keyset.refresh().await?);
+
+        let ret = Keyset::load(auth_config(None)).await;
+        assert!(matches!(ret, Err(AppError::JwksError(_))));
+        Ok(())
+    }
+}
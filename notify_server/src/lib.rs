@@ -1,24 +1,26 @@
 mod config;
 mod error;
+mod keys;
 mod notify;
 mod sse;
+mod typing;
 
 use anyhow::Result;
 use axum::{
     middleware::from_fn_with_state,
     response::{Html, IntoResponse},
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use chat_core::{
-    middlewares::{verify_token, TokenVerify},
-    utils::DecodingKey,
-};
+use chat_core::middlewares::{verify_token, TokenVerify};
 use dashmap::DashMap;
+use keys::Keyset;
 use notify::ChatEvent;
+use sqlx::PgPool;
 use sse::sse_handler;
 use std::{ops::Deref, sync::Arc};
 use tokio::sync::broadcast;
+use typing::{spawn_prune_task, typing_handler, RateLimiter};
 
 pub use config::AppConfig;
 pub use error::AppError;
@@ -36,12 +38,17 @@ pub struct AppState {
 pub struct AppStateInner {
     pub config: AppConfig,
     pub users: UserMap,
-    pub dk: DecodingKey,
+    pub keyset: Keyset,
+    pub pg_pool: PgPool,
+    pub typing_limiter: RateLimiter,
 }
 
 pub fn get_router(state: AppState) -> Router {
+    spawn_prune_task(state.clone());
+
     Router::new()
         .route("/events", get(sse_handler))
+        .route("/typing/:chat_id", post(typing_handler))
         .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/", get(index_handler))
         .with_state(state)
@@ -60,18 +67,88 @@ impl Deref for AppState {
 
 impl TokenVerify for AppState {
     type Error = AppError;
-    fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
-        Ok(self.dk.verify(token)?)
+    async fn verify(&self, token: &str) -> Result<chat_core::User, Self::Error> {
+        let claims = self.keyset.verify_claims(token).await?;
+        let jti = claims
+            .jwt_id
+            .ok_or_else(|| AppError::InvalidToken("token without id".to_string()))?;
+        if self.is_token_revoked(&jti).await? {
+            return Err(AppError::InvalidToken(format!("token {} is revoked", jti)));
+        }
+        Ok(claims.custom)
     }
 }
 
 impl AppState {
-    pub fn new(config: AppConfig) -> Self {
-        let dk = DecodingKey::load(&config.auth.pk).expect("load pk failed");
+    pub async fn try_new(config: AppConfig) -> Result<Self, AppError> {
+        let keyset = Keyset::load(config.auth.clone()).await?;
         let users = Arc::new(DashMap::new());
+        let pg_pool = PgPool::connect_lazy(&config.server.db_url).expect("invalid db_url");
+
+        Ok(Self {
+            inner: Arc::new(AppStateInner {
+                keyset,
+                config,
+                users,
+                pg_pool,
+                typing_limiter: RateLimiter::default(),
+            }),
+        })
+    }
+
+    // tokens revoked by chat_server, e.g. user signed out
+    async fn is_token_revoked(&self, jti: &str) -> Result<bool, AppError> {
+        let revoked = sqlx::query_scalar(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)
+            "#,
+        )
+        .bind(jti)
+        .fetch_one(&self.pg_pool)
+        .await?;
+
+        Ok(revoked)
+    }
+}
+
+#[cfg(test)]
+mod test_util {
+    use super::*;
+    use sqlx::Executor;
+    use sqlx_db_tester::TestPg;
+
+    impl AppState {
+        // database of chat_server with its migrations and fixtures
+        pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
+            let config = AppConfig::load()?;
+            let keyset = Keyset::load(config.auth.clone()).await?;
+            let post = config.server.db_url.rfind('/').expect("invalid db_url");
+            let tdb = TestPg::new(
+                config.server.db_url[..post].to_string(),
+                std::path::Path::new("../migrations"),
+            );
+            let pg_pool = tdb.get_pool().await;
+
+            let sql = include_str!("../../chat_server/fixtures/test.sql").split(';');
+            let mut ts = pg_pool.begin().await.expect("begin transaction failed");
+            for s in sql {
+                if s.trim().is_empty() {
+                    continue;
+                }
+                ts.execute(s).await.expect("execute sql failed");
+            }
+            ts.commit().await.expect("commit transaction failed");
 
-        Self {
-            inner: Arc::new(AppStateInner { dk, config, users }),
+            let state = Self {
+                inner: Arc::new(AppStateInner {
+                    keyset,
+                    config,
+                    users: Arc::new(DashMap::new()),
+                    pg_pool,
+                    typing_limiter: RateLimiter::default(),
+                }),
+            };
+            Ok((tdb, state))
         }
     }
 }
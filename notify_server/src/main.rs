@@ -17,7 +17,7 @@ async fn main() -> Result<()> {
     info!("Listening on: {:?}", addr);
 
     let config = AppConfig::load()?;
-    let state = AppState::new(config);
+    let state = AppState::try_new(config).await?;
 
     let app = get_router(state.clone());
     setup_pg_listener(state).await?;
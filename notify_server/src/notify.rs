@@ -3,9 +3,10 @@ use std::{collections::HashSet, sync::Arc};
 use crate::AppState;
 use anyhow::Result;
 use chat_core::{Chat, Message};
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgListener;
+use sqlx::{postgres::PgListener, PgPool};
 use tracing::{info, warn};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -14,7 +15,39 @@ pub enum ChatEvent {
     NewChat(Chat),
     AddToChat(Chat),
     RemoveFromChat(Chat),
+    // chat changed for members staying in it, e.g. renamed
+    UpdateChat(Chat),
+    ArchiveChat(Chat),
+    UnarchiveChat(Chat),
+    DeleteChat(Chat),
     NewMessage(Message),
+    // reply sent to participants of its thread
+    NewReply(Message),
+    MessageUpdated(Message),
+    MessageDeleted(Message),
+    ReactionAdded(Reaction),
+    ReactionRemoved(Reaction),
+    // sent to all members, including other clients of the reader
+    ChatRead(ChatRead),
+    // ephemeral, sent by clients rather than database changes
+    Typing { chat_id: i64, user_id: i64 },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatRead {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub last_read_message_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    // filled from the chat in the notification
+    #[serde(default)]
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub user_id: i64,
+    pub emoji: String,
 }
 
 #[derive(Debug)]
@@ -27,38 +60,75 @@ struct Notification {
 #[derive(Debug, Serialize, Deserialize)]
 struct ChatUpdater {
     op: String,
-    old: Option<Chat>,
-    new: Option<Chat>,
+    old: Option<ChatRow>,
+    new: Option<ChatRow>,
 }
 
+// chat row in the trigger payload, including columns not exposed by `Chat`
 #[derive(Debug, Serialize, Deserialize)]
-struct ChatMessageCreated {
+struct ChatRow {
+    #[serde(flatten)]
+    chat: Chat,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReactionChanged {
+    op: String,
+    reaction: Reaction,
+    chat: Chat,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatReadChanged {
+    read: ChatRead,
+    chat: Chat,
+}
+
+// message notifications only carry the id, the row is loaded by `ChatMessageChanged::fetch`
+#[derive(Debug, Serialize, Deserialize)]
+struct MessageNotify {
+    message_id: i64,
+}
+
+// payload of both created and updated messages
+#[derive(Debug, Serialize, Deserialize)]
+struct ChatMessageChanged {
     message: Message,
     chat: Chat,
+    // senders of the thread root and its replies, only for new replies
+    #[serde(default)]
+    participants: Option<Vec<i64>>,
 }
 
 pub async fn setup_pg_listener(state: AppState) -> Result<()> {
     let mut listener = PgListener::connect(&state.config.server.db_url).await?;
     listener.listen("chat_updated").await?;
     listener.listen("chat_message_created").await?;
+    listener.listen("chat_message_updated").await?;
+    listener.listen("message_reaction_changed").await?;
+    listener.listen("chat_read").await?;
 
     let mut stream = listener.into_stream();
 
     tokio::spawn(async move {
         while let Some(Ok(notif)) = stream.next().await {
             info!("Received notification: {:?}", notif);
-            let notification = match Notification::load(notif.channel(), notif.payload()) {
-                Ok(notification) => notification,
-                Err(e) => {
-                    warn!("load notification failed: {}", e);
-                    continue;
-                }
-            };
+            let notifications =
+                match load_notifications(&state, notif.channel(), notif.payload()).await {
+                    Ok(notifications) => notifications,
+                    Err(e) => {
+                        warn!("load notification failed: {}", e);
+                        continue;
+                    }
+                };
             let users = &state.users;
-            for user_id in &notification.user_ids {
-                if let Some(tx) = users.get(user_id) {
-                    if let Err(e) = tx.send(notification.event.clone()) {
-                        warn!("send to user {} failed: {}", user_id, e);
+            for notification in notifications {
+                for user_id in &notification.user_ids {
+                    if let Some(tx) = users.get(user_id) {
+                        if let Err(e) = tx.send(notification.event.clone()) {
+                            warn!("send to user {} failed: {}", user_id, e);
+                        }
                     }
                 }
             }
@@ -69,52 +139,430 @@ pub async fn setup_pg_listener(state: AppState) -> Result<()> {
     Ok(())
 }
 
+async fn load_notifications(
+    state: &AppState,
+    r#type: &str,
+    payload: &str,
+) -> Result<Vec<Notification>> {
+    match r#type {
+        "chat_message_created" | "chat_message_updated" => {
+            let notify: MessageNotify = serde_json::from_str(payload)?;
+            match ChatMessageChanged::fetch(&state.pg_pool, notify.message_id).await? {
+                Some(changed) => Notification::from_message(r#type, changed),
+                // chat purged in the meantime
+                None => Ok(vec![]),
+            }
+        }
+        _ => Notification::load(r#type, payload),
+    }
+}
+
+impl ChatMessageChanged {
+    async fn fetch(pool: &PgPool, message_id: i64) -> Result<Option<Self>> {
+        let message: Option<Message> = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, thread_root_id, content, files, edited_at, deleted_at, created_at
+            FROM messages
+            WHERE id = $1
+            "#,
+        )
+        .bind(message_id)
+        .fetch_optional(pool)
+        .await?;
+        let Some(message) = message else {
+            return Ok(None);
+        };
+
+        let chat: Option<Chat> = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, name, type, members, archived_at, created_at
+            FROM chats
+            WHERE id = $1
+            "#,
+        )
+        .bind(message.chat_id)
+        .fetch_optional(pool)
+        .await?;
+        let Some(chat) = chat else {
+            return Ok(None);
+        };
+
+        let participants = match message.thread_root_id {
+            Some(root_id) => {
+                sqlx::query_scalar(
+                    r#"
+                    SELECT array_agg(DISTINCT sender_id)
+                    FROM messages
+                    WHERE id = $1 OR thread_root_id = $1
+                    "#,
+                )
+                .bind(root_id)
+                .fetch_one(pool)
+                .await?
+            }
+            None => None,
+        };
+
+        Ok(Some(Self {
+            message,
+            chat,
+            participants,
+        }))
+    }
+}
+
 impl Notification {
-    fn load(r#type: &str, payload: &str) -> Result<Self> {
+    // one chat change may lead to different events for different users
+    fn load(r#type: &str, payload: &str) -> Result<Vec<Self>> {
         match r#type {
             "chat_updated" => {
                 let updater: ChatUpdater = serde_json::from_str(payload)?;
-                let user_ids = get_affected_user_ids(updater.old.as_ref(), updater.new.as_ref());
-                let event = match updater.op.as_str() {
-                    "INSERT" => ChatEvent::NewChat(updater.new.expect("new should exist")),
-                    "UPDATE" => ChatEvent::AddToChat(updater.new.expect("update should exist")),
-                    "DELETE" => ChatEvent::RemoveFromChat(updater.old.expect("old should exist")),
+                let notifications = match (updater.op.as_str(), updater.old, updater.new) {
+                    ("INSERT", _, Some(new)) => {
+                        vec![Self::new(
+                            new.chat.members.clone(),
+                            ChatEvent::NewChat(new.chat),
+                        )]
+                    }
+                    // members were told when the chat was soft deleted
+                    ("UPDATE" | "DELETE", Some(old), _) if old.deleted_at.is_some() => vec![],
+                    ("UPDATE", Some(old), Some(new)) if new.deleted_at.is_some() => {
+                        vec![Self::new(
+                            old.chat.members.clone(),
+                            ChatEvent::DeleteChat(new.chat),
+                        )]
+                    }
+                    ("UPDATE", Some(old), Some(new))
+                        if old.chat.archived_at.is_none() && new.chat.archived_at.is_some() =>
+                    {
+                        vec![Self::new(
+                            new.chat.members.clone(),
+                            ChatEvent::ArchiveChat(new.chat),
+                        )]
+                    }
+                    ("UPDATE", Some(old), Some(new))
+                        if old.chat.archived_at.is_some() && new.chat.archived_at.is_none() =>
+                    {
+                        vec![Self::new(
+                            new.chat.members.clone(),
+                            ChatEvent::UnarchiveChat(new.chat),
+                        )]
+                    }
+                    ("UPDATE", Some(old), Some(new)) => {
+                        let (old, new) = (old.chat, new.chat);
+                        let (added, removed, stayed) = get_affected_user_ids(&old, &new);
+                        vec![
+                            Self::new(added, ChatEvent::AddToChat(new.clone())),
+                            Self::new(removed, ChatEvent::RemoveFromChat(new.clone())),
+                            Self::new(stayed, ChatEvent::UpdateChat(new)),
+                        ]
+                    }
+                    ("DELETE", Some(old), _) => {
+                        vec![Self::new(
+                            old.chat.members.clone(),
+                            ChatEvent::DeleteChat(old.chat),
+                        )]
+                    }
                     _ => return Err(anyhow::anyhow!("Invalid op")),
                 };
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(event),
-                })
+                Ok(notifications
+                    .into_iter()
+                    .filter(|n| !n.user_ids.is_empty())
+                    .collect())
             }
-            "chat_message_created" => {
-                let message: ChatMessageCreated = serde_json::from_str(payload)?;
-                println!("message: {:?}", &message);
-                let user_ids = message.chat.members.iter().cloned().collect();
-                Ok(Self {
-                    user_ids,
-                    event: Arc::new(ChatEvent::NewMessage(message.message)),
-                })
+            "message_reaction_changed" => {
+                let changed: ReactionChanged = serde_json::from_str(payload)?;
+                let reaction = Reaction {
+                    chat_id: changed.chat.id,
+                    ..changed.reaction
+                };
+                let event = match changed.op.as_str() {
+                    "INSERT" => ChatEvent::ReactionAdded(reaction),
+                    "DELETE" => ChatEvent::ReactionRemoved(reaction),
+                    _ => return Err(anyhow::anyhow!("Invalid op")),
+                };
+                Ok(vec![Self::new(changed.chat.members, event)])
+            }
+            "chat_read" => {
+                let changed: ChatReadChanged = serde_json::from_str(payload)?;
+                Ok(vec![Self::new(
+                    changed.chat.members,
+                    ChatEvent::ChatRead(changed.read),
+                )])
             }
             _ => Err(anyhow::anyhow!("Invalid notification type")),
         }
     }
-}
 
-fn get_affected_user_ids(old: Option<&Chat>, new: Option<&Chat>) -> HashSet<i64> {
-    let mut user_ids = HashSet::new();
-    match (old, new) {
-        (Some(old), Some(new)) => {
-            user_ids.extend(old.members.iter());
-            user_ids.extend(new.members.iter());
+    fn from_message(r#type: &str, message: ChatMessageChanged) -> Result<Vec<Self>> {
+        match r#type {
+            "chat_message_created" => {
+                let (participants, others): (Vec<i64>, Vec<i64>) = message
+                    .chat
+                    .members
+                    .iter()
+                    .partition(|id| message.participants.iter().flatten().any(|p| p == *id));
+                let notifications = vec![
+                    Self::new(participants, ChatEvent::NewReply(message.message.clone())),
+                    Self::new(others, ChatEvent::NewMessage(message.message)),
+                ];
+                Ok(notifications
+                    .into_iter()
+                    .filter(|n| !n.user_ids.is_empty())
+                    .collect())
+            }
+            "chat_message_updated" => {
+                let user_ids = message.chat.members.clone();
+                let event = if message.message.deleted_at.is_some() {
+                    ChatEvent::MessageDeleted(message.message)
+                } else {
+                    ChatEvent::MessageUpdated(message.message)
+                };
+                Ok(vec![Self::new(user_ids, event)])
+            }
+            _ => Err(anyhow::anyhow!("Invalid message notification type")),
         }
-        (Some(old), None) => {
-            user_ids.extend(old.members.iter());
+    }
+}
+
+impl Notification {
+    fn new(user_ids: impl IntoIterator<Item = i64>, event: ChatEvent) -> Self {
+        Self {
+            user_ids: user_ids.into_iter().collect(),
+            event: Arc::new(event),
         }
-        (None, Some(new)) => {
-            user_ids.extend(new.members.iter());
+    }
+}
+
+// (added, removed, stayed) members of an updated chat
+fn get_affected_user_ids(old: &Chat, new: &Chat) -> (Vec<i64>, Vec<i64>, Vec<i64>) {
+    let old_ids: HashSet<_> = old.members.iter().copied().collect();
+    let new_ids: HashSet<_> = new.members.iter().copied().collect();
+
+    (
+        new_ids.difference(&old_ids).copied().collect(),
+        old_ids.difference(&new_ids).copied().collect(),
+        new_ids.intersection(&old_ids).copied().collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::ChatType;
+    use serde_json::json;
+
+    fn chat(members: &[i64]) -> Chat {
+        Chat {
+            id: 1,
+            ws_id: 1,
+            name: Some("general".to_string()),
+            r#type: ChatType::PublicChannel,
+            members: members.to_vec(),
+            archived_at: None,
+            created_at: Utc::now(),
+            unread_count: 0,
         }
-        _ => {}
     }
 
-    user_ids
+    fn users_of(notifications: &[Notification], f: fn(&ChatEvent) -> bool) -> HashSet<i64> {
+        notifications
+            .iter()
+            .filter(|n| f(&n.event))
+            .flat_map(|n| n.user_ids.iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn chat_update_should_notify_per_user() -> Result<()> {
+        let payload = json!({
+            "op": "UPDATE",
+            "old": chat(&[1, 2, 3]),
+            "new": chat(&[1, 3, 4]),
+        });
+        let notifications = Notification::load("chat_updated", &payload.to_string())?;
+
+        let added = users_of(&notifications, |e| matches!(e, ChatEvent::AddToChat(_)));
+        let removed = users_of(&notifications, |e| {
+            matches!(e, ChatEvent::RemoveFromChat(_))
+        });
+        let updated = users_of(&notifications, |e| matches!(e, ChatEvent::UpdateChat(_)));
+        assert_eq!(added, HashSet::from([4]));
+        assert_eq!(removed, HashSet::from([2]));
+        assert_eq!(updated, HashSet::from([1, 3]));
+
+        // rename only notifies existing members
+        let payload = json!({
+            "op": "UPDATE",
+            "old": chat(&[1, 2]),
+            "new": chat(&[1, 2]),
+        });
+        let notifications = Notification::load("chat_updated", &payload.to_string())?;
+        assert_eq!(notifications.len(), 1);
+        assert!(matches!(*notifications[0].event, ChatEvent::UpdateChat(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn chat_state_change_should_notify_members() -> Result<()> {
+        let archived = Chat {
+            archived_at: Some(Utc::now()),
+            ..chat(&[1, 2])
+        };
+        let payload = json!({
+            "op": "UPDATE",
+            "old": chat(&[1, 2]),
+            "new": archived,
+        });
+        let notifications = Notification::load("chat_updated", &payload.to_string())?;
+        assert_eq!(notifications.len(), 1);
+        assert!(matches!(*notifications[0].event, ChatEvent::ArchiveChat(_)));
+
+        let payload = json!({
+            "op": "UPDATE",
+            "old": archived,
+            "new": chat(&[1, 2]),
+        });
+        let notifications = Notification::load("chat_updated", &payload.to_string())?;
+        assert!(matches!(
+            *notifications[0].event,
+            ChatEvent::UnarchiveChat(_)
+        ));
+
+        let mut deleted = json!(chat(&[1, 2]));
+        deleted["deleted_at"] = json!(Utc::now());
+        let payload = json!({
+            "op": "UPDATE",
+            "old": chat(&[1, 2]),
+            "new": deleted,
+        });
+        let notifications = Notification::load("chat_updated", &payload.to_string())?;
+        assert_eq!(
+            users_of(&notifications, |e| matches!(e, ChatEvent::DeleteChat(_))),
+            HashSet::from([1, 2])
+        );
+
+        // purging a soft deleted chat is silent
+        let payload = json!({
+            "op": "DELETE",
+            "old": deleted,
+            "new": null,
+        });
+        let notifications = Notification::load("chat_updated", &payload.to_string())?;
+        assert!(notifications.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn message_change_should_notify_chat_members() -> Result<()> {
+        let mut message = Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 1,
+            content: "edited".to_string(),
+            files: vec![],
+            thread_root_id: None,
+            edited_at: Some(Utc::now()),
+            deleted_at: None,
+            created_at: Utc::now(),
+            reply_count: 0,
+            last_reply_at: None,
+            reactions: vec![],
+        };
+        let payload = json!({ "message": message, "chat": chat(&[1, 2]) });
+        let notifications =
+            Notification::from_message("chat_message_updated", serde_json::from_value(payload)?)?;
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(
+            *notifications[0].event,
+            ChatEvent::MessageUpdated(_)
+        ));
+
+        message.deleted_at = Some(Utc::now());
+        let payload = json!({ "message": message, "chat": chat(&[1, 2]) });
+        let notifications =
+            Notification::from_message("chat_message_updated", serde_json::from_value(payload)?)?;
+        assert!(matches!(
+            *notifications[0].event,
+            ChatEvent::MessageDeleted(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn reply_should_notify_thread_participants() -> Result<()> {
+        let message = json!({
+            "id": 2,
+            "chat_id": 1,
+            "sender_id": 2,
+            "thread_root_id": 1,
+            "content": "reply",
+            "files": [],
+            "edited_at": null,
+            "deleted_at": null,
+            "created_at": Utc::now(),
+        });
+        let payload = json!({
+            "message": message,
+            "chat": chat(&[1, 2, 3]),
+            "participants": [1, 2],
+        });
+        let notifications =
+            Notification::from_message("chat_message_created", serde_json::from_value(payload)?)?;
+        let replied = users_of(&notifications, |e| matches!(e, ChatEvent::NewReply(_)));
+        let others = users_of(&notifications, |e| matches!(e, ChatEvent::NewMessage(_)));
+        assert_eq!(replied, HashSet::from([1, 2]));
+        assert_eq!(others, HashSet::from([3]));
+
+        // messages out of threads go to all members
+        let payload = json!({
+            "message": message,
+            "chat": chat(&[1, 2, 3]),
+            "participants": null,
+        });
+        let notifications =
+            Notification::from_message("chat_message_created", serde_json::from_value(payload)?)?;
+        assert_eq!(notifications.len(), 1);
+        assert!(matches!(*notifications[0].event, ChatEvent::NewMessage(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn reaction_change_should_notify_chat_members() -> Result<()> {
+        let payload = json!({
+            "op": "DELETE",
+            "reaction": {
+                "message_id": 1,
+                "user_id": 2,
+                "emoji": "👍",
+                "created_at": Utc::now(),
+            },
+            "chat": chat(&[1, 2]),
+        });
+        let notifications = Notification::load("message_reaction_changed", &payload.to_string())?;
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 2]));
+        let ChatEvent::ReactionRemoved(reaction) = &*notifications[0].event else {
+            panic!("expect ReactionRemoved");
+        };
+        assert_eq!(reaction.chat_id, 1);
+        assert_eq!(reaction.emoji, "👍");
+        Ok(())
+    }
+
+    #[test]
+    fn chat_read_should_notify_chat_members() -> Result<()> {
+        let payload = json!({
+            "read": {
+                "chat_id": 1,
+                "user_id": 2,
+                "last_read_message_id": 10,
+                "updated_at": Utc::now(),
+            },
+            "chat": chat(&[1, 2]),
+        });
+        let notifications = Notification::load("chat_read", &payload.to_string())?;
+        assert_eq!(notifications[0].user_ids, HashSet::from([1, 2]));
+        assert!(matches!(*notifications[0].event, ChatEvent::ChatRead(_)));
+        Ok(())
+    }
 }
@@ -41,7 +41,18 @@ pub(crate) async fn sse_handler(
                 ChatEvent::NewChat(_) => "NewChat",
                 ChatEvent::AddToChat(_) => "AddToChat",
                 ChatEvent::RemoveFromChat(_) => "RemoveFromChat",
+                ChatEvent::UpdateChat(_) => "UpdateChat",
+                ChatEvent::ArchiveChat(_) => "ArchiveChat",
+                ChatEvent::UnarchiveChat(_) => "UnarchiveChat",
+                ChatEvent::DeleteChat(_) => "DeleteChat",
                 ChatEvent::NewMessage(_) => "NewMessage",
+                ChatEvent::NewReply(_) => "NewReply",
+                ChatEvent::MessageUpdated(_) => "MessageUpdated",
+                ChatEvent::MessageDeleted(_) => "MessageDeleted",
+                ChatEvent::ReactionAdded(_) => "ReactionAdded",
+                ChatEvent::ReactionRemoved(_) => "ReactionRemoved",
+                ChatEvent::ChatRead(_) => "ChatRead",
+                ChatEvent::Typing { .. } => "Typing",
             };
             let data = serde_json::to_string(&event).expect("serialize event failed");
             Some(Ok(Event::default().data(data).event(name)))
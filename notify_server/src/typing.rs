@@ -0,0 +1,211 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
+use chat_core::User;
+use dashmap::DashMap;
+use tracing::warn;
+
+use crate::{AppError, AppState, ChatEvent};
+
+// clients are expected to send typing every few seconds while the user types
+const TYPING_INTERVAL: Duration = Duration::from_secs(2);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Allow one request per user and chat within the interval
+pub struct RateLimiter {
+    interval: Duration,
+    last_seen: DashMap<(i64, i64), Instant>,
+}
+
+/// Tell other members of the chat that user is typing. The event is not
+/// stored, only users connected at the moment receive it.
+pub(crate) async fn typing_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(chat_id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.typing_limiter.check(user.id, chat_id) {
+        return Err(AppError::TooManyRequests(format!(
+            "user {} is typing too often in chat {}",
+            user.id, chat_id
+        )));
+    }
+
+    let members: Option<Vec<i64>> = sqlx::query_scalar(
+        r#"
+        SELECT members FROM chats
+        WHERE id = $1 AND ws_id = $2 AND archived_at IS NULL AND deleted_at IS NULL
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user.ws_id)
+    .fetch_optional(&state.pg_pool)
+    .await?;
+    let members = match members {
+        Some(members) if members.contains(&user.id) => members,
+        _ => {
+            return Err(AppError::NotChatMember(format!(
+                "user {} is not a member of chat {}",
+                user.id, chat_id
+            )))
+        }
+    };
+
+    let event = Arc::new(ChatEvent::Typing {
+        chat_id,
+        user_id: user.id,
+    });
+    for id in members.iter().filter(|id| **id != user.id) {
+        if let Some(tx) = state.users.get(id) {
+            if let Err(e) = tx.send(event.clone()) {
+                warn!("send to user {} failed: {}", id, e);
+            }
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_seen: DashMap::new(),
+        }
+    }
+
+    pub fn check(&self, user_id: i64, chat_id: i64) -> bool {
+        let now = Instant::now();
+        let mut allowed = true;
+        self.last_seen
+            .entry((user_id, chat_id))
+            .and_modify(|last| {
+                if now.duration_since(*last) < self.interval {
+                    allowed = false;
+                } else {
+                    *last = now;
+                }
+            })
+            .or_insert(now);
+        allowed
+    }
+
+    /// Forget entries which no longer limit anything
+    pub fn prune(&self) {
+        self.last_seen
+            .retain(|_, last| last.elapsed() < self.interval);
+    }
+}
+
+// without pruning the limiter keeps an entry for every user and chat ever typed in
+pub(crate) fn spawn_prune_task(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.typing_limiter.prune();
+        }
+    });
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(TYPING_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::Result;
+    use tokio::sync::broadcast;
+
+    async fn get_user(state: &AppState, id: i64) -> Result<User> {
+        let user = sqlx::query_as(
+            "SELECT id, ws_id, fullname, email, created_at FROM users WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(&state.pg_pool)
+        .await?;
+        Ok(user)
+    }
+
+    #[test]
+    fn rate_limiter_should_limit_per_user_and_chat() {
+        let limiter = RateLimiter::new(Duration::from_millis(50));
+        assert!(limiter.check(1, 1));
+        assert!(!limiter.check(1, 1));
+        assert!(limiter.check(1, 2));
+        assert!(limiter.check(2, 1));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.check(1, 1));
+        limiter.prune();
+        assert_eq!(limiter.last_seen.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn typing_should_be_sent_to_other_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut receivers = Vec::new();
+        for id in 1..=5 {
+            let (tx, rx) = broadcast::channel(16);
+            state.users.insert(id, tx);
+            receivers.push(rx);
+        }
+
+        // chat 2 has members 1, 2 and 3
+        let user = get_user(&state, 1).await?;
+        typing_handler(Extension(user.clone()), State(state.clone()), Path(2)).await?;
+        for (i, rx) in receivers.iter_mut().enumerate() {
+            let ret = rx.try_recv();
+            if i == 1 || i == 2 {
+                let event = ret?;
+                assert!(matches!(
+                    *event,
+                    ChatEvent::Typing {
+                        chat_id: 2,
+                        user_id: 1
+                    }
+                ));
+            } else {
+                assert!(ret.is_err());
+            }
+        }
+
+        let ret = typing_handler(Extension(user), State(state.clone()), Path(2)).await;
+        assert!(matches!(ret, Err(AppError::TooManyRequests(_))));
+
+        let guest = get_user(&state, 5).await?;
+        let ret = typing_handler(Extension(guest), State(state.clone()), Path(2)).await;
+        assert!(matches!(ret, Err(AppError::NotChatMember(_))));
+
+        sqlx::query("UPDATE chats SET archived_at = NOW() WHERE id = 2")
+            .execute(&state.pg_pool)
+            .await?;
+        let ret = typing_handler(
+            Extension(get_user(&state, 2).await?),
+            State(state.clone()),
+            Path(2),
+        )
+        .await;
+        assert!(matches!(ret, Err(AppError::NotChatMember(_))));
+
+        sqlx::query("UPDATE chats SET deleted_at = NOW() WHERE id = 4")
+            .execute(&state.pg_pool)
+            .await?;
+        let ret =
+            typing_handler(Extension(get_user(&state, 3).await?), State(state), Path(4)).await;
+        assert!(matches!(ret, Err(AppError::NotChatMember(_))));
+        Ok(())
+    }
+}